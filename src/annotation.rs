@@ -0,0 +1,117 @@
+// Modules for handling gene annotation
+use std::collections::HashMap;
+use itertools::Itertools;
+
+use crate::junction;
+
+// Gene spans by chromosome, sorted by start for lookup
+pub struct GeneIndex {
+    genes: HashMap<String, Vec<(i64, i64, String, String)>>, // (start, end, gene_id, gene_name)
+    max_gene_length: i64,
+}
+
+impl GeneIndex {
+    pub fn new(mut genes: HashMap<String, Vec<(i64, i64, String, String)>>) -> Self {
+        let mut max_gene_length = 0;
+        for chrom_genes in genes.values_mut() {
+            chrom_genes.sort();
+            for (start, end, _, _) in chrom_genes.iter() {
+                max_gene_length = max_gene_length.max(end - start);
+            }
+        }
+        GeneIndex { genes, max_gene_length }
+    }
+
+    pub fn len(&self) -> usize {
+        self.genes.values().map(|chrom_genes| chrom_genes.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Function to find the genes (gene_id, gene_name) whose span contains the junction
+    pub fn find_genes(&self, junction_coords: &str) -> Vec<(&str, &str)> {
+        let (chrom, start, end) = match junction::parse_junction(junction_coords) {
+            Some(coords) => coords,
+            None => return Vec::new(),
+        };
+        let chrom_genes = match self.genes.get(chrom) {
+            Some(chrom_genes) => chrom_genes,
+            None => return Vec::new(),
+        };
+        // Only genes starting within the maximum gene length before the junction can contain it
+        let first = chrom_genes.partition_point(|gene| gene.0 < end - self.max_gene_length);
+        let last = chrom_genes.partition_point(|gene| gene.0 <= start);
+        chrom_genes[first..last]
+            .iter()
+            .filter(|(gene_start, gene_end, _, _)| *gene_start <= start && *gene_end >= end)
+            .map(|(_, _, gene_id, gene_name)| (gene_id.as_str(), gene_name.as_str()))
+            .collect()
+    }
+
+    // Function to get comma-separated gene IDs and gene names of a junction, or None if it is intergenic
+    pub fn annotate(&self, junction_coords: &str) -> Option<(String, String)> {
+        let genes = self.find_genes(junction_coords);
+        if genes.is_empty() {
+            return None;
+        }
+        Some((
+            genes.iter().map(|(gene_id, _)| *gene_id).join(","),
+            genes.iter().map(|(_, gene_name)| *gene_name).join(","),
+        ))
+    }
+
+    // Function to name junctions after the genes they fall in, for the features file
+    pub fn feature_names<'a>(&self, junctions: impl Iterator<Item = &'a String>) -> HashMap<String, String> {
+        junctions
+            .filter_map(|junction| self.annotate(junction).map(|(_, gene_name)| (junction.clone(), gene_name)))
+            .collect()
+    }
+
+    // Function to get the genes a junction is assigned to, none if it falls in several genes and drop_ambiguous is set
+    fn assign_genes(&self, junction: &str, drop_ambiguous: bool) -> Vec<(&str, &str)> {
+        let genes = self.find_genes(junction);
+        if drop_ambiguous && genes.len() > 1 {
+            return Vec::new();
+        }
+        genes
+    }
+
+    // Function to sum junction totals by gene, returning the gene totals and the names of the genes
+    pub fn sum_totals_by_gene(
+        &self,
+        junction_totals: &HashMap<String, u32>,
+        drop_ambiguous: bool,
+    ) -> (HashMap<String, u32>, HashMap<String, String>) {
+        let mut gene_totals: HashMap<String, u32> = HashMap::new();
+        let mut gene_names: HashMap<String, String> = HashMap::new();
+        for (junction, count) in junction_totals {
+            for (gene_id, gene_name) in self.assign_genes(junction, drop_ambiguous) {
+                *gene_totals.entry(gene_id.to_string()).or_insert(0) += count;
+                gene_names.insert(gene_id.to_string(), gene_name.to_string());
+            }
+        }
+        (gene_totals, gene_names)
+    }
+
+    // Function to sum junction counts per barcode by gene, returning the gene counts and the names of the genes
+    pub fn sum_counts_by_gene(
+        &self,
+        junction_counts: &HashMap<String, HashMap<String, u32>>,
+        drop_ambiguous: bool,
+    ) -> (HashMap<String, HashMap<String, u32>>, HashMap<String, String>) {
+        let mut gene_counts: HashMap<String, HashMap<String, u32>> = HashMap::new();
+        let mut gene_names: HashMap<String, String> = HashMap::new();
+        for (junction, cell_counts) in junction_counts {
+            for (gene_id, gene_name) in self.assign_genes(junction, drop_ambiguous) {
+                let counts = gene_counts.entry(gene_id.to_string()).or_default();
+                for (barcode, count) in cell_counts {
+                    *counts.entry(barcode.clone()).or_insert(0) += count;
+                }
+                gene_names.insert(gene_id.to_string(), gene_name.to_string());
+            }
+        }
+        (gene_counts, gene_names)
+    }
+}
@@ -1,63 +1,137 @@
 // Modules for handling exon-intron boundaries
+use rust_htslib::bam::{self, Read};
+use rust_htslib::bam::record::Cigar;
+use std::collections::{HashMap, HashSet};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
-pub fn count_exon_intron_boundaries(
-    cell_barcode: Option<&String>,
-    introns: &HashSet<(String, i64, i64)>,
-    chrom: &str,
-    start: i64,
-    end: i64,
-    left_counts: &mut HashMap<String, HashMap<String, u32>>,
-    right_counts: &mut HashMap<String, HashMap<String, u32>>,
-    left_totals: &mut HashMap<String, u32>,
-    right_totals: &mut HashMap<String, u32>,
-    processed_boundary_reads: &mut HashMap<String, HashSet<String>>,
-    read_name: &str,
-    mode: &str,
-) {
-    // Check if the read was already processed for this boundary
-    if let Some(reads) = processed_boundary_reads.get_mut(chrom) {
-        if reads.contains(read_name) {
-            return; // Skip counting
+use crate::counter::CountParams;
+use crate::data_loader;
+use crate::junction;
+
+// Sorted boundary positions of the junctions on one chromosome and strand, with the index of each junction
+type BoundaryGroup = Vec<(i64, usize)>;
+
+// Function to get the aligned blocks (1-based, inclusive) of a read, split at RefSkip operations
+fn aligned_blocks(record: &bam::Record) -> Vec<(i64, i64)> {
+    let mut blocks = Vec::new();
+    let mut current_pos = record.pos();
+    let mut block_start = current_pos;
+    for cigar in record.cigar().iter() {
+        match cigar {
+            Cigar::Match(l) | Cigar::Equal(l) | Cigar::Diff(l) | Cigar::Del(l) => current_pos += *l as i64,
+            Cigar::RefSkip(l) => {
+                if current_pos > block_start {
+                    blocks.push((block_start + 1, current_pos));
+                }
+                current_pos += *l as i64;
+                block_start = current_pos;
+            }
+            _ => {}
         }
-        reads.insert(read_name.to_string());
-    } else {
-        let mut reads_set = HashSet::new();
-        reads_set.insert(read_name.to_string());
-        processed_boundary_reads.insert(chrom.to_string(), reads_set);
     }
+    if current_pos > block_start {
+        blocks.push((block_start + 1, current_pos));
+    }
+    blocks
+}
 
-    // Count the read for the boundary
-    if mode == "single" {
-        if let Some(cb_str) = cell_barcode {
-            for (intron_chrom, intron_start, intron_end) in introns {
-                let key = format!("{}:{}-{}", intron_chrom, intron_start, intron_end);
-                if chrom == intron_chrom {
-                    if start <= *intron_start && end >= *intron_start {
-                        let boundary_entry = left_counts
-                            .entry(key.to_string())
-                            .or_insert_with(HashMap::new);
-                        *boundary_entry.entry(cb_str.clone()).or_insert(0) += 1;
-                    }
-                    if start <= *intron_end && end >= *intron_end {
-                        let boundary_entry = right_counts
-                            .entry(key.to_string())
-                            .or_insert_with(HashMap::new);
-                        *boundary_entry.entry(cb_str.clone()).or_insert(0) += 1;
-                    }
-                }
+// Function to count reads whose aligned blocks span the donor or acceptor boundary of each junction
+// without a RefSkip (intron retention), applying the same read filters as junction counting
+pub fn count_retained_reads(
+    bam_file: &str,
+    reference: Option<&String>,
+    params: &CountParams,
+    junctions: &[&String],
+) -> Result<HashMap<String, u32>, Box<dyn std::error::Error>> {
+    let mut bam_reader = data_loader::open_bam(bam_file, reference)?;
+    let reference_names = data_loader::reference_names(bam_file, bam_reader.header(), params.chr_prefix)?;
+    let reference_ids: HashMap<&str, i32> = reference_names
+        .iter()
+        .enumerate()
+        .map(|(tid, name)| (name.as_str(), tid as i32))
+        .collect();
+
+    // Boundaries by chromosome ID (and strand), as the last position before each boundary,
+    // so reads are looked up without formatting a key for each of them
+    let mut boundaries: HashMap<(i32, Option<char>), BoundaryGroup> = HashMap::new();
+    for (index, junction_coords) in junctions.iter().enumerate() {
+        let (_, strand) = junction::split_strand(junction_coords);
+        if let Some((chrom, start, end)) = junction::parse_junction(junction_coords) {
+            // Junctions on chromosomes missing from this BAM file have no reads to count
+            let tid = match reference_ids.get(chrom) {
+                Some(&tid) => tid,
+                None => continue,
+            };
+            let group = boundaries.entry((tid, strand.and_then(|s| s.chars().next()))).or_default();
+            group.push((start, index)); // Donor: last exon base and first intron base
+            group.push((end - 1, index)); // Acceptor: last intron base and first exon base
+        }
+    }
+    for group in boundaries.values_mut() {
+        group.sort();
+    }
+
+    let mut retained_reads: Vec<HashSet<String>> = vec![HashSet::new(); junctions.len()];
+    let mut rng_tid = -1;
+    let mut rng = StdRng::seed_from_u64(params.seed);
+    for result in bam_reader.records() {
+        let record = result?;
+
+        // Apply the filters of junction counting in the same order so subsampling draws the same reads
+        let ref_name = match data_loader::reference_name(&reference_names, &record)? {
+            Some(ref_name) => ref_name,
+            None => continue,
+        };
+        if params.include_chroms.is_some_and(|chroms| !chroms.contains(ref_name))
+            || params.exclude_chroms.is_some_and(|chroms| chroms.contains(ref_name))
+            || !params.extractor.passes_max_loci(&record)
+        {
+            continue;
+        }
+        if let Some(fraction) = params.subsample {
+            if record.tid() != rng_tid {
+                rng_tid = record.tid();
+                rng = StdRng::seed_from_u64(params.seed.wrapping_add(rng_tid as u64));
+            }
+            if !rng.gen_bool(fraction) {
+                continue;
             }
         }
-    } else {
-        for (intron_chrom, intron_start, intron_end) in introns {
-            let key = format!("{}:{}-{}", intron_chrom, intron_start, intron_end);
-            if chrom == intron_chrom {
-                if start <= *intron_start && end >= *intron_start {
-                    *left_totals.entry(key.to_string()).or_insert(0) += 1;
-                }
-                if start <= *intron_end && end >= *intron_end {
-                    *right_totals.entry(key.to_string()).or_insert(0) += 1;
+        if (params.no_duplicates && record.is_duplicate())
+            || (params.proper_pairs_only && record.is_paired() && !record.is_proper_pair())
+            || !params.extractor.passes_insert_size(&record)
+            || !params.extractor.passes_min_aligned_length(&record)
+        {
+            continue;
+        }
+
+        // Reads without a strand may retain introns of junctions stranded by their splice motif
+        let strand = params.extractor.strand(&record);
+        let strands = match strand {
+            Some(_) => vec![strand],
+            None => vec![None, Some('+'), Some('-')],
+        };
+        let groups: Vec<&BoundaryGroup> = strands.into_iter().filter_map(|strand| boundaries.get(&(record.tid(), strand))).collect();
+        if groups.is_empty() {
+            continue;
+        }
+        let read_name = String::from_utf8_lossy(record.qname()).to_string();
+        for (block_start, block_end) in aligned_blocks(&record) {
+            // The block spans a boundary if it covers both the position before and after it
+            for group in &groups {
+                let first = group.partition_point(|boundary| boundary.0 < block_start);
+                let last = group.partition_point(|boundary| boundary.0 < block_end);
+                for (_, index) in &group[first..last] {
+                    retained_reads[*index].insert(read_name.clone());
                 }
             }
         }
     }
-}
\ No newline at end of file
+
+    Ok(junctions
+        .iter()
+        .zip(retained_reads)
+        .map(|(junction_coords, reads)| (junction_coords.to_string(), reads.len() as u32))
+        .collect())
+}
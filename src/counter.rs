@@ -0,0 +1,998 @@
+// Modules for counting junction reads
+use rust_htslib::bam::{self, Read};
+use rayon::prelude::*;
+use rust_htslib::bam::record::Aux;
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{BuildHasher, Hash};
+use std::io::Write;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{debug, info, warn};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rustc_hash::{FxBuildHasher, FxHashMap, FxHashSet};
+
+use crate::data_loader;
+use crate::junction::{self, ChrPrefix, CoordBase, JunctionExtractor, JunctionKey};
+use crate::tags::{self, NameBarcode};
+use crate::writer;
+
+// Parameters controlling which reads and junctions are counted
+pub struct CountParams<'a> {
+    pub mode: &'a str,
+    pub extractor: JunctionExtractor,
+    pub no_duplicates: bool,
+    pub dedup_by_position: bool, // Count one read per alignment start (and barcode) for each junction
+    pub proper_pairs_only: bool, // Skip paired reads not mapped in a proper pair
+    pub subsample: Option<f64>, // Probability of keeping each read
+    pub seed: u64,
+    pub cell_barcode_tags: &'a [String], // Tags for cell barcodes in order of precedence
+    pub barcode_from_name: Option<&'a NameBarcode>, // Extraction of barcodes from read names lacking the tags
+    pub strip_barcode_suffix: bool,
+    pub umi_tag: &'a str,
+    pub count_umis: bool, // Count distinct UMIs per junction and barcode, skipping reads without a UMI
+    pub chr_prefix: ChrPrefix, // Renaming of chromosomes in junction keys
+    pub coord_base: CoordBase, // Convention of junction coordinates in the read map
+    pub include_chroms: Option<&'a HashSet<String>>,
+    pub mito_names: &'a HashSet<String>, // Names of the mitochondrial contig for QC statistics
+    pub exclude_chroms: Option<&'a HashSet<String>>,
+    pub split_by_rg: bool, // Count by read group (RG) in bulk mode
+    pub cell_barcodes_of_interest: &'a HashSet<String>,
+    pub show_progress_bar: bool,
+    pub progress_interval: u64, // Percentage of reads between progress log lines (without a progress bar)
+    pub two_pass: bool, // Collect supported junctions in a first pass instead of buffering reads
+    pub strict: bool, // Fail instead of warning on input that is not coordinate-sorted
+    pub track_distinct_reads: bool, // Keep the keys of counted reads to count distinct spliced reads
+    pub track_dropped_barcodes: bool, // Count the reads of barcodes skipped as not in the list of interest
+    pub max_microexon_length: Option<i64>, // Count reads spanning exons up to this length between two junctions
+    pub fractional_multimappers: bool, // Also count reads weighted by 1/NH into the weighted counts
+}
+
+// Counts by junction and optionally by cell barcode, keyed by JunctionKey while counting and by junction string afterwards.
+// Maps are hashed with FxHash while counting, where they are updated for every read.
+#[derive(Default)]
+pub struct JunctionCounts<K = String, S = RandomState> {
+    pub junction_counts: HashMap<K, HashMap<String, u32, S>, S>,
+    pub junction_totals: HashMap<K, u32, S>,
+    pub multimapped_totals: HashMap<K, u32, S>, // Counts of multimapped reads (NH > 1) by junction
+    pub minus_read_totals: HashMap<K, u32, S>, // Counts of reads on the minus strand by the library type, by junction
+    pub read_totals: HashMap<K, u32, S>, // Counts of alignments by junction, counting both mates of a fragment (bulk mode)
+    pub junction_positions: HashMap<K, HashMap<i64, u32, S>, S>, // Counts of supporting reads by alignment start (bulk mode)
+    pub max_overhangs: HashMap<K, i64, S>, // Longest overhang (longer anchor) of supporting reads (bulk mode)
+    pub supported_junctions: HashSet<K, S>, // Junctions supported by an anchored read (first pass of two-pass mode)
+    pub unsupported_totals: HashMap<K, u32, S>, // Reads of junctions never supported by an anchored read
+    pub weighted_counts: HashMap<K, HashMap<String, f64, S>, S>, // Counts by barcode with reads weighted by 1/NH, if requested
+    pub weighted_totals: HashMap<K, f64, S>, // Counts with reads weighted by 1/NH (bulk mode), if requested
+    pub microexon_totals: HashMap<(K, K), u32, S>, // Reads spanning a microexon, by its upstream and downstream junctions
+    pub cell_barcodes: HashSet<String>,
+    pub dropped_barcodes: HashMap<String, u64>, // Reads of barcodes not in the list of interest, if tracked
+    pub reference_names: Vec<String>, // Chromosome names in the order of the BAM header
+    pub counted_reads: u64, // Number of reads counted into at least one junction, each counted once
+    pub mito_reads: u64, // Number of counted reads on the mitochondrial contig
+    pub distinct_reads: HashSet<String>, // Keys of reads counted into any junction, if tracked
+    pub total_mapped_reads: u64, // Number of mapped reads in the BAM index
+    pub processed_reads: u64, // Number of reads read from the BAM file
+    pub skipped_nh: u64, // Number of reads skipped by the NH filter
+    pub missing_nh: u64, // Number of reads lacking an NH tag
+    pub missing_barcode: u64, // Number of reads lacking a cell barcode tag (single mode)
+    pub skipped_anchor: u64, // Number of reads never counted as none of their junctions is supported by an anchored read
+    pub skipped_intron_length: u64, // Number of reads whose introns are all outside the intron length range
+    pub skipped_barcode: u64, // Number of reads whose barcode is not in the list of interest
+}
+
+impl<K: Hash + Eq, S: BuildHasher + Default> JunctionCounts<K, S> {
+    // Function to merge partial counts (e.g., from another chromosome) into this one
+    pub fn merge(&mut self, other: JunctionCounts<K, S>) {
+        for (junction, cell_counts) in other.junction_counts {
+            let junction_entry = self.junction_counts.entry(junction).or_default();
+            for (barcode, count) in cell_counts {
+                *junction_entry.entry(barcode).or_insert(0) += count;
+            }
+        }
+        for (junction, count) in other.junction_totals {
+            *self.junction_totals.entry(junction).or_insert(0) += count;
+        }
+        for (junction, count) in other.multimapped_totals {
+            *self.multimapped_totals.entry(junction).or_insert(0) += count;
+        }
+        for (junction, count) in other.minus_read_totals {
+            *self.minus_read_totals.entry(junction).or_insert(0) += count;
+        }
+        for (junction, count) in other.read_totals {
+            *self.read_totals.entry(junction).or_insert(0) += count;
+        }
+        for (junction, count) in other.unsupported_totals {
+            *self.unsupported_totals.entry(junction).or_insert(0) += count;
+        }
+        for (junction, cell_counts) in other.weighted_counts {
+            let junction_entry = self.weighted_counts.entry(junction).or_default();
+            for (barcode, count) in cell_counts {
+                *junction_entry.entry(barcode).or_insert(0.0) += count;
+            }
+        }
+        for (junction, count) in other.weighted_totals {
+            *self.weighted_totals.entry(junction).or_insert(0.0) += count;
+        }
+        for (junctions, count) in other.microexon_totals {
+            *self.microexon_totals.entry(junctions).or_insert(0) += count;
+        }
+        for (junction, positions) in other.junction_positions {
+            let junction_entry = self.junction_positions.entry(junction).or_default();
+            for (pos, count) in positions {
+                *junction_entry.entry(pos).or_insert(0) += count;
+            }
+        }
+        for (junction, overhang) in other.max_overhangs {
+            let max_overhang = self.max_overhangs.entry(junction).or_insert(0);
+            *max_overhang = (*max_overhang).max(overhang);
+        }
+        self.supported_junctions.extend(other.supported_junctions);
+        self.cell_barcodes.extend(other.cell_barcodes);
+        for (barcode, count) in other.dropped_barcodes {
+            *self.dropped_barcodes.entry(barcode).or_insert(0) += count;
+        }
+        self.counted_reads += other.counted_reads;
+        self.mito_reads += other.mito_reads;
+        self.distinct_reads.extend(other.distinct_reads);
+        self.total_mapped_reads += other.total_mapped_reads;
+        self.processed_reads += other.processed_reads;
+        self.skipped_nh += other.skipped_nh;
+        self.missing_nh += other.missing_nh;
+        self.missing_barcode += other.missing_barcode;
+        self.skipped_anchor += other.skipped_anchor;
+        self.skipped_intron_length += other.skipped_intron_length;
+        self.skipped_barcode += other.skipped_barcode;
+    }
+
+    // Function to convert the junction keys (e.g., to strings), summing the counts of junctions given the same key
+    pub fn map_keys<L: Hash + Eq + Default, T: BuildHasher + Default>(self, name: impl Fn(K) -> L) -> JunctionCounts<L, T> {
+        let mut named = JunctionCounts {
+            cell_barcodes: self.cell_barcodes,
+            dropped_barcodes: self.dropped_barcodes,
+            reference_names: self.reference_names,
+            distinct_reads: self.distinct_reads,
+            counted_reads: self.counted_reads,
+            mito_reads: self.mito_reads,
+            total_mapped_reads: self.total_mapped_reads,
+            processed_reads: self.processed_reads,
+            skipped_nh: self.skipped_nh,
+            missing_nh: self.missing_nh,
+            missing_barcode: self.missing_barcode,
+            skipped_anchor: self.skipped_anchor,
+            skipped_intron_length: self.skipped_intron_length,
+            skipped_barcode: self.skipped_barcode,
+            ..Default::default()
+        };
+        for (junction, cell_counts) in self.junction_counts {
+            let junction_entry = named.junction_counts.entry(name(junction)).or_default();
+            for (barcode, count) in cell_counts {
+                *junction_entry.entry(barcode).or_insert(0) += count;
+            }
+        }
+        for (totals, named_totals) in [
+            (self.junction_totals, &mut named.junction_totals),
+            (self.multimapped_totals, &mut named.multimapped_totals),
+            (self.minus_read_totals, &mut named.minus_read_totals),
+            (self.read_totals, &mut named.read_totals),
+            (self.unsupported_totals, &mut named.unsupported_totals),
+        ] {
+            for (junction, count) in totals {
+                *named_totals.entry(name(junction)).or_insert(0) += count;
+            }
+        }
+        for (junction, cell_counts) in self.weighted_counts {
+            let junction_entry = named.weighted_counts.entry(name(junction)).or_default();
+            for (barcode, count) in cell_counts {
+                *junction_entry.entry(barcode).or_insert(0.0) += count;
+            }
+        }
+        for (junction, count) in self.weighted_totals {
+            *named.weighted_totals.entry(name(junction)).or_insert(0.0) += count;
+        }
+        for ((upstream, downstream), count) in self.microexon_totals {
+            *named.microexon_totals.entry((name(upstream), name(downstream))).or_insert(0) += count;
+        }
+        for (junction, positions) in self.junction_positions {
+            let junction_entry = named.junction_positions.entry(name(junction)).or_default();
+            for (pos, count) in positions {
+                *junction_entry.entry(pos).or_insert(0) += count;
+            }
+        }
+        for (junction, overhang) in self.max_overhangs {
+            let max_overhang = named.max_overhangs.entry(name(junction)).or_insert(0);
+            *max_overhang = (*max_overhang).max(overhang);
+        }
+        named.supported_junctions = self.supported_junctions.into_iter().map(name).collect();
+        named
+    }
+}
+
+impl JunctionCounts<JunctionKey, FxBuildHasher> {
+    // Function to convert the junction keys to "chrom:start-end[:strand]" strings with the reference names
+    // Counts are summed in case junctions on different chromosomes get the same name (e.g., "1" and "chr1" with --add-chr-prefix)
+    pub fn into_named(self, reference_names: &[String]) -> JunctionCounts<String> {
+        self.map_keys(|junction| junction.key(&reference_names[junction.tid as usize]))
+    }
+}
+
+// Read buffered until its junction is supported by an anchored read
+struct BufferedRead {
+    cell_barcode: Option<String>,
+    read_key: String, // Read name or CB:UMI key
+    multimapped: bool,
+    weight: f64, // 1/NH with --fractional-multimappers, otherwise 1
+    minus_read: bool, // Whether the read lies on the minus strand by the library type
+    pos: i64, // Alignment start
+    overhang: i64, // Longer anchor of the junction
+    read_name: Option<String>, // Kept only for the read map
+    record: Option<Rc<bam::Record>>, // Kept only for the spanning BAM, shared by all junctions of the read
+    index: u64, // Index of the read in the input, to write it to the spanning BAM in order
+    counted: Rc<Cell<bool>>, // Shared by all junctions of the read so that it is counted once in counted_reads
+}
+
+// Order of reads in the input: alignment start and index of the read
+type ReadOrder = (i64, u64);
+
+// Counted reads waiting to be written to the spanning BAM in input order, since buffered reads counted once
+// their junction is supported must be written before the reads that followed them
+#[derive(Default)]
+struct SpanningBuffer {
+    tid: i32,
+    pending: BTreeMap<ReadOrder, bam::Record>, // Counted reads
+    waiting: BTreeMap<ReadOrder, (Rc<Cell<bool>>, i64)>, // Buffered reads not yet counted, with the start of their last junction
+}
+
+impl SpanningBuffer {
+    // Function to write the counted reads that no read still waiting to be counted precedes, before the next read
+    fn advance(&mut self, writer: &mut bam::Writer, tid: i32, pos: i64) -> Result<(), rust_htslib::errors::Error> {
+        if tid != self.tid {
+            // Buffered reads of the previous chromosome can no longer be counted
+            self.waiting.clear();
+            self.finish(writer)?;
+            self.tid = tid;
+        }
+        // Reads stop waiting once counted, or once reads start past their junctions, which then cannot be supported
+        while let Some(entry) = self.waiting.first_entry() {
+            let (counted, last_start) = entry.get();
+            if counted.get() || *last_start <= pos {
+                entry.remove();
+            } else {
+                break;
+            }
+        }
+        let first_waiting = self.waiting.first_key_value().map_or(pos, |(&(start, _), _)| start.min(pos));
+        while let Some(entry) = self.pending.first_entry() {
+            if entry.key().0 >= first_waiting {
+                break;
+            }
+            writer.write(&entry.remove())?;
+        }
+        Ok(())
+    }
+
+    // Function to write all counted reads
+    fn finish(&mut self, writer: &mut bam::Writer) -> Result<(), rust_htslib::errors::Error> {
+        for record in std::mem::take(&mut self.pending).into_values() {
+            writer.write(&record)?;
+        }
+        Ok(())
+    }
+}
+
+// Progress shared between worker threads, shown as a progress bar or as debug logs
+pub struct Progress {
+    total_mapped_reads: u64,
+    read_count: AtomicU64,
+    last_percentage: AtomicU64,
+    interval: u64, // Percentage between reports
+    bar: Option<ProgressBar>,
+}
+
+impl Progress {
+    pub fn new(total_mapped_reads: u64, show_bar: bool, interval: u64) -> Self {
+        let bar = if show_bar && total_mapped_reads > 0 {
+            let bar = ProgressBar::new(total_mapped_reads);
+            bar.set_style(
+                ProgressStyle::with_template("[{elapsed_precise}] {bar:40} {pos}/{len} reads ({percent}%, ETA {eta})")
+                    .unwrap_or_else(|_| ProgressStyle::default_bar()),
+            );
+            Some(bar)
+        } else if show_bar {
+            // Spinner with a raw read counter when the total is unknown
+            let bar = ProgressBar::new_spinner();
+            bar.set_style(
+                ProgressStyle::with_template("[{elapsed_precise}] {spinner} {pos} reads")
+                    .unwrap_or_else(|_| ProgressStyle::default_spinner()),
+            );
+            Some(bar)
+        } else {
+            None
+        };
+        Progress {
+            total_mapped_reads,
+            read_count: AtomicU64::new(0),
+            last_percentage: AtomicU64::new(0),
+            interval: interval.max(1),
+            bar,
+        }
+    }
+
+    // Function to count a read and report progress at each increment of the interval (e.g., every 1%)
+    pub fn update(&self) {
+        let read_count = self.read_count.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Some(bar) = &self.bar {
+            bar.inc(1);
+            return;
+        }
+        // Report a raw read count instead of a percentage when the total is unknown
+        if self.total_mapped_reads == 0 {
+            if read_count.is_multiple_of(1_000_000) {
+                debug!("Progress: {} reads", read_count);
+            }
+            return;
+        }
+        let progress_percentage = (read_count * 100) / self.total_mapped_reads / self.interval * self.interval;
+        if progress_percentage > self.last_percentage.fetch_max(progress_percentage, Ordering::Relaxed) {
+            debug!("Progress: {}% ({} / {})", progress_percentage, read_count, self.total_mapped_reads);
+        }
+    }
+
+    // Function to clear the progress bar when processing is done
+    pub fn finish(&self) {
+        if let Some(bar) = &self.bar {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+// Pass over a BAM file, either buffering reads of not-yet-supported junctions or as one of two passes
+#[derive(Clone, Copy)]
+pub enum CountPass<'a> {
+    OnePass, // Count reads, buffering those of junctions not yet supported by an anchored read
+    Collect, // First pass of two-pass mode: only collect junctions supported by an anchored read
+    Count(&'a FxHashSet<JunctionKey>), // Second pass of two-pass mode: count reads of the supported junctions
+}
+
+// Function to count junction reads from all records of a BAM reader
+pub fn count_junctions<R: bam::Read>(
+    bam_reader: &mut R,
+    reference_names: &[String],
+    params: &CountParams,
+    progress: &Progress,
+    pass: CountPass,
+    mut spanning_writer: Option<&mut bam::Writer>,
+    mut read_map: Option<&mut writer::OutputFile>,
+) -> Result<JunctionCounts<JunctionKey, FxBuildHasher>, Box<dyn std::error::Error + Send + Sync>> {
+    let mode = params.mode;
+    let no_duplicates = params.no_duplicates;
+    let umi_tag = params.umi_tag;
+    let cell_barcodes_of_interest = params.cell_barcodes_of_interest;
+
+    // HashMaps to store counts by junction and optionally by cell barcode
+    let mut counts: JunctionCounts<JunctionKey, FxBuildHasher> = JunctionCounts::default();
+
+    // HashSet to store supported junctions and HashMap to store buffered reads
+    // Junctions supported in the first pass of two-pass mode are borrowed, shared by all chromosome workers,
+    // and only junctions newly supported in this pass are stored
+    let first_pass_supported = match pass {
+        CountPass::Count(supported) => Some(supported),
+        _ => None,
+    };
+    let mut supported_junctions: FxHashSet<JunctionKey> = FxHashSet::default();
+    let mut buffered_reads: FxHashMap<JunctionKey, Vec<BufferedRead>> = FxHashMap::default();
+
+    // HashMap to store processed reads by junction
+    let mut processed_reads: FxHashMap<JunctionKey, FxHashSet<String>> = FxHashMap::default();
+    let mut processed_microexons: FxHashMap<(JunctionKey, JunctionKey), FxHashSet<String>> = FxHashMap::default();
+
+    // Counted reads are written to the spanning BAM in input order
+    let mut spanning_buffer = SpanningBuffer::default();
+
+    // Random number generator for subsampling, reseeded per chromosome so results do not depend on threads
+    let mut rng_tid = -1;
+    let mut rng = StdRng::seed_from_u64(params.seed);
+
+    // Iterate over each read in the BAM file
+    for result in bam_reader.records() {
+        let record = result?;
+        progress.update();
+        counts.processed_reads += 1;
+        if let Some(writer) = spanning_writer.as_deref_mut() {
+            spanning_buffer.advance(writer, record.tid(), record.pos())?;
+        }
+
+        // Extract reference name (chromosome), skipping unmapped reads without a reference
+        let ref_name = match data_loader::reference_name(reference_names, &record)? {
+            Some(ref_name) => ref_name,
+            None => continue,
+        };
+
+        // Skip read if its chromosome is not included or is excluded
+        if let Some(include_chroms) = params.include_chroms {
+            if !include_chroms.contains(ref_name) {
+                continue;
+            }
+        }
+        if let Some(exclude_chroms) = params.exclude_chroms {
+            if exclude_chroms.contains(ref_name) {
+                continue;
+            }
+        }
+
+        let is_mito = params.mito_names.contains(ref_name);
+
+        // Skip read if NH tag exceeds max_loci (or is missing if required)
+        if tags::get_nh(&record).is_none() {
+            counts.missing_nh += 1;
+        }
+        if !params.extractor.passes_max_loci(&record) {
+            counts.skipped_nh += 1;
+            continue; // Skip this read
+        }
+
+        // Skip read if it is not drawn by subsampling
+        if let Some(fraction) = params.subsample {
+            if record.tid() != rng_tid {
+                rng_tid = record.tid();
+                rng = StdRng::seed_from_u64(params.seed.wrapping_add(rng_tid as u64));
+            }
+            if !rng.gen_bool(fraction) {
+                continue;
+            }
+        }
+
+        // Skip read if it is marked as a duplicate
+        if no_duplicates && record.is_duplicate() {
+            continue;
+        }
+
+        // Skip read if it is paired but not mapped in a proper pair (e.g., discordant pairs and singletons)
+        if params.proper_pairs_only && record.is_paired() && !record.is_proper_pair() {
+            continue;
+        }
+
+        // Skip read if it is properly paired but its insert size is out of range (e.g., mis-paired mates)
+        if !params.extractor.passes_insert_size(&record) {
+            continue;
+        }
+
+        // Extract cell barcode from tags if in single mode, or read group (RG) if split by RG
+        let cell_barcode = if mode == "single" {
+            // Use the first tag present (e.g., the corrected CB, falling back to the raw CR), then the read name if requested
+            let barcode = params
+                .cell_barcode_tags
+                .iter()
+                .find_map(|tag| match record.aux(tag.as_bytes()) {
+                    Ok(Aux::String(cb_str)) => Some(cb_str),
+                    _ => None,
+                })
+                .or_else(|| params.barcode_from_name?.extract(std::str::from_utf8(record.qname()).ok()?));
+            // Trim a suffix such as "-1" of CellRanger if requested
+            barcode.map(|cb_str| match cb_str.rsplit_once('-') {
+                Some((barcode, _)) if params.strip_barcode_suffix => barcode.to_string(),
+                _ => cb_str.to_string(),
+            })
+        } else if params.split_by_rg {
+            match record.aux(b"RG") {
+                Ok(Aux::String(rg_str)) => Some(rg_str.to_string()),
+                _ => None,
+            }
+        } else {
+            None
+        };
+        if mode == "single" && cell_barcode.is_none() {
+            counts.missing_barcode += 1;
+        }
+
+        // Skip read if its barcode is not in the list of interest
+        if let Some(cb) = &cell_barcode {
+            if !cell_barcodes_of_interest.is_empty() && !cell_barcodes_of_interest.contains(cb) {
+                counts.skipped_barcode += 1;
+                if params.track_dropped_barcodes {
+                    *counts.dropped_barcodes.entry(cb.clone()).or_insert(0) += 1;
+                }
+                continue;
+            }
+        }
+
+        // Extract UMI from tags if in single mode
+        let umi = if mode == "single" {
+            match record.aux(umi_tag.as_bytes()) {
+                Ok(Aux::String(umi_str)) => Some(umi_str.to_string()),
+                _ => None,
+            }
+        } else {
+            None
+        };
+
+        // Skip read if molecules are counted but it has no UMI to be assigned to
+        if params.count_umis && umi.is_none() {
+            continue;
+        }
+
+        // Deduplicate by alignment start if requested, by cell barcode and UMI if available, otherwise by read name
+        let dedup_key = if params.dedup_by_position {
+            // Mates of a fragment share the key of their two alignment starts
+            let start = if record.is_paired() {
+                format!("{}-{}", record.pos().min(record.mpos()), record.pos().max(record.mpos()))
+            } else {
+                record.pos().to_string()
+            };
+            match &cell_barcode {
+                Some(cb) => format!("{}:{}", cb, start),
+                None => start,
+            }
+        } else {
+            match (&cell_barcode, &umi) {
+                (Some(cb), Some(umi)) => format!("{}:{}", cb, umi),
+                _ => std::str::from_utf8(record.qname()).unwrap().to_string(),
+            }
+        };
+
+        // If a cell barcode is present (for single mode), or always process for bulk mode
+        if mode == "bulk" || cell_barcode.is_some() {
+            if let Some(cb_str) = &cell_barcode {
+                counts.cell_barcodes.insert(cb_str.clone());
+            }
+
+            // Track whether the read is counted for any junction, shared with its buffered junctions
+            let mut counted = false;
+            let mut buffered_counted: Option<Rc<Cell<bool>>> = None;
+            let mut buffered_record: Option<Rc<bam::Record>> = None;
+            let mut last_buffered_start = 0;
+            // Multimapped reads (NH > 1) are counted separately from unique reads
+            let multimapped = tags::get_nh(&record).is_some_and(|nh| nh > 1);
+            // A read mapped to NH loci contributes 1/NH to each of them if counts are fractional
+            let weight = match tags::get_nh(&record) {
+                Some(nh) if params.fractional_multimappers && nh > 1 => 1.0 / nh as f64,
+                _ => 1.0,
+            };
+            // Strand of the read by the library type, compared with the splice motif of the junction after counting
+            // (not the junction strand, which may itself come from XS or the motif with --strand-source)
+            let minus_read = params.extractor.library_type.strand(&record) == Some('-');
+            // Track whether the read has junctions in the intron length range and whether any is supported
+            let mut has_junction = false;
+            let mut has_supported_junction = false;
+
+            let junctions = params.extractor.extract(&record);
+
+            // Count the read once for each short exon between two consecutive anchored junctions (microexon)
+            if let Some(max_length) = params.max_microexon_length.filter(|_| !matches!(pass, CountPass::Collect)) {
+                for (upstream, downstream) in junction::microexons(&junctions, max_length) {
+                    let microexon = (upstream.coords(), downstream.coords());
+                    if processed_microexons.entry(microexon).or_default().insert(dedup_key.clone()) {
+                        *counts.microexon_totals.entry(microexon).or_insert(0) += 1;
+                    }
+                }
+            }
+
+            for junction in junctions {
+                let junction_coords = junction.coords();
+
+                // Only collect supported junctions in the first pass of two-pass mode
+                if let CountPass::Collect = pass {
+                    if junction.anchored {
+                        counts.supported_junctions.insert(junction_coords);
+                    }
+                    continue;
+                }
+
+                has_junction = true;
+                let first_pass_supported_junction = first_pass_supported.is_some_and(|supported| supported.contains(&junction_coords));
+                if junction.anchored && !first_pass_supported_junction {
+                    // Mark as supported and process buffered reads
+                    supported_junctions.insert(junction_coords);
+                    if let Some(buffered) = buffered_reads.remove(&junction_coords) {
+                        for buffered_read in buffered {
+                            // Mates of a fragment are each counted as reads but once as a fragment
+                            if mode == "bulk" {
+                                *counts.read_totals.entry(junction_coords).or_insert(0) += 1;
+                            }
+                            if junction::process_junction(
+                                &junction_coords,
+                                buffered_read.cell_barcode.as_ref(),
+                                &mut counts.junction_counts,
+                                &mut counts.junction_totals,
+                                &mut processed_reads, // Pass the processed reads map
+                                &buffered_read.read_key, // Pass the key of the buffered read
+                                mode,
+                            ) {
+                                // Count the read only the first time any of its junctions is counted
+                                if !buffered_read.counted.replace(true) {
+                                    counts.counted_reads += 1;
+                                    if is_mito {
+                                        counts.mito_reads += 1;
+                                    }
+                                    if let Some(buffered_record) = &buffered_read.record {
+                                        spanning_buffer.pending.insert((buffered_record.pos(), buffered_read.index), (**buffered_record).clone());
+                                    }
+                                }
+                                if params.track_distinct_reads {
+                                    counts.distinct_reads.insert(buffered_read.read_key.clone());
+                                }
+                                if let (Some(map), Some(read_name)) = (read_map.as_deref_mut(), &buffered_read.read_name) {
+                                    write_read_map_line(map, read_name, buffered_read.cell_barcode.as_ref(), &params.coord_base.apply(&junction_coords.key(ref_name)))?;
+                                }
+                                if buffered_read.multimapped {
+                                    *counts.multimapped_totals.entry(junction_coords).or_insert(0) += 1;
+                                }
+                                if params.fractional_multimappers {
+                                    add_weighted_count(&mut counts, junction_coords, buffered_read.cell_barcode.as_ref(), buffered_read.weight, mode);
+                                }
+                                if buffered_read.minus_read {
+                                    *counts.minus_read_totals.entry(junction_coords).or_insert(0) += 1;
+                                }
+                                if mode == "bulk" {
+                                    *counts.junction_positions.entry(junction_coords).or_default().entry(buffered_read.pos).or_insert(0) += 1;
+                                    let max_overhang = counts.max_overhangs.entry(junction_coords).or_insert(0);
+                                    *max_overhang = (*max_overhang).max(buffered_read.overhang);
+                                }
+                            }
+                        }
+                    }
+                }
+
+                // Process or buffer the current read
+                if first_pass_supported_junction || supported_junctions.contains(&junction_coords) {
+                    has_supported_junction = true;
+                    if mode == "bulk" {
+                        *counts.read_totals.entry(junction_coords).or_insert(0) += 1;
+                    }
+                    let counted_junction = junction::process_junction(
+                        &junction_coords,
+                        cell_barcode.as_ref(),
+                        &mut counts.junction_counts,
+                        &mut counts.junction_totals,
+                        &mut processed_reads, // Pass the processed reads map
+                        &dedup_key, // Pass read name or CB:UMI key
+                        mode,
+                    );
+                    if counted_junction && multimapped {
+                        *counts.multimapped_totals.entry(junction_coords).or_insert(0) += 1;
+                    }
+                    if counted_junction && params.fractional_multimappers {
+                        add_weighted_count(&mut counts, junction_coords, cell_barcode.as_ref(), weight, mode);
+                    }
+                    if counted_junction && minus_read {
+                        *counts.minus_read_totals.entry(junction_coords).or_insert(0) += 1;
+                    }
+                    if let Some(map) = read_map.as_deref_mut().filter(|_| counted_junction) {
+                        let read_name = String::from_utf8_lossy(record.qname());
+                        write_read_map_line(map, &read_name, cell_barcode.as_ref(), &params.coord_base.apply(&junction_coords.key(ref_name)))?;
+                    }
+                    if counted_junction && mode == "bulk" {
+                        *counts.junction_positions.entry(junction_coords).or_default().entry(record.pos()).or_insert(0) += 1;
+                        let max_overhang = counts.max_overhangs.entry(junction_coords).or_insert(0);
+                        *max_overhang = (*max_overhang).max(junction.overhang);
+                    }
+                    counted |= counted_junction;
+                } else if let CountPass::Count(_) = pass {
+                    // Reads of junctions not supported in the first pass are only counted as unsupported
+                    *counts.unsupported_totals.entry(junction_coords).or_insert(0) += 1;
+                } else if let CountPass::OnePass = pass {
+                    last_buffered_start = junction_coords.start;
+                    buffered_reads
+                        .entry(junction_coords)
+                        .or_default()
+                        .push(BufferedRead {
+                            cell_barcode: cell_barcode.clone(),
+                            read_key: dedup_key.clone(),
+                            multimapped,
+                            weight,
+                            minus_read,
+                            pos: record.pos(),
+                            overhang: junction.overhang,
+                            read_name: read_map.as_ref().map(|_| String::from_utf8_lossy(record.qname()).to_string()),
+                            record: spanning_writer.as_ref().map(|_| buffered_record.get_or_insert_with(|| Rc::new(record.clone())).clone()),
+                            index: counts.processed_reads,
+                            counted: buffered_counted.get_or_insert_with(Rc::default).clone(),
+                        });
+                }
+            }
+
+            // Reads dropped in the second pass of two-pass mode are skipped here, buffered reads once left unflushed
+            if has_junction && !has_supported_junction && buffered_counted.is_none() {
+                counts.skipped_anchor += 1;
+            } else if !has_junction && params.extractor.lacks_intron_in_range(&record) {
+                counts.skipped_intron_length += 1;
+            }
+
+            // Write the read to the spanning BAM if it was counted, or once it is counted if it was buffered
+            if let Some(buffered_counted) = buffered_counted.as_ref().filter(|_| !counted && spanning_writer.is_some()) {
+                spanning_buffer.waiting.insert((record.pos(), counts.processed_reads), (buffered_counted.clone(), last_buffered_start));
+            }
+            if counted {
+                if let Some(buffered_counted) = &buffered_counted {
+                    buffered_counted.set(true);
+                }
+                counts.counted_reads += 1;
+                if is_mito {
+                    counts.mito_reads += 1;
+                }
+                if params.track_distinct_reads {
+                    counts.distinct_reads.insert(dedup_key);
+                }
+                if spanning_writer.is_some() {
+                    spanning_buffer.pending.insert((record.pos(), counts.processed_reads), record);
+                }
+            }
+        }
+    }
+    if let Some(writer) = spanning_writer {
+        spanning_buffer.finish(writer)?;
+    }
+
+    // Reads still buffered belong to junctions never supported by an anchored read
+    // A read buffered for several junctions is skipped once, unless it was counted for another junction
+    let skipped_reads: FxHashSet<*const Cell<bool>> = buffered_reads
+        .values()
+        .flatten()
+        .filter(|buffered_read| !buffered_read.counted.get())
+        .map(|buffered_read| Rc::as_ptr(&buffered_read.counted))
+        .collect();
+    counts.skipped_anchor += skipped_reads.len() as u64;
+    for (junction_coords, buffered) in buffered_reads {
+        counts.unsupported_totals.insert(junction_coords, buffered.len() as u32);
+    }
+
+    Ok(counts)
+}
+
+// Function to add a counted read with its weight to the weighted counts, like process_junction does to the counts
+fn add_weighted_count(
+    counts: &mut JunctionCounts<JunctionKey, FxBuildHasher>,
+    junction_coords: JunctionKey,
+    cell_barcode: Option<&String>,
+    weight: f64,
+    mode: &str,
+) {
+    if mode == "bulk" {
+        *counts.weighted_totals.entry(junction_coords).or_insert(0.0) += weight;
+    }
+    if let Some(cb_str) = cell_barcode {
+        *counts.weighted_counts.entry(junction_coords).or_default().entry(cb_str.clone()).or_insert(0.0) += weight;
+    }
+}
+
+// Function to write a counted read with its barcode (or read group) and junction to the read map
+fn write_read_map_line(
+    read_map: &mut writer::OutputFile,
+    read_name: &str,
+    cell_barcode: Option<&String>,
+    junction_coords: &str,
+) -> std::io::Result<()> {
+    writeln!(read_map, "{}\t{}\t{}", read_name, cell_barcode.map_or(".", |cb| cb.as_str()), junction_coords)
+}
+
+// Function to count junction reads of a BAM/CRAM file, processing chromosomes in parallel if threads > 1
+pub fn count_bam(
+    bam_file: &str,
+    reference: Option<&String>,
+    params: &CountParams,
+    threads: usize,
+    spanning_bam: Option<&String>,
+    read_map_path: Option<&String>,
+    compression: Option<flate2::Compression>, // Compression of the read map
+) -> Result<JunctionCounts, Box<dyn std::error::Error>> {
+    // Count total mapped reads in the BAM file
+    data_loader::check_cram_reference(bam_file, reference);
+    // A stream from stdin or a file without an index has no total, so progress is reported as a read count
+    let mut indexed = false;
+    let total_mapped_reads = if data_loader::is_stdin(bam_file) {
+        info!("Reading from stdin; total number of reads is unknown");
+        0
+    } else {
+        match data_loader::count_total_mapped_reads(bam_file, reference) {
+            Ok(total_mapped_reads) => {
+                indexed = true;
+                info!("Total number of reads: {}", total_mapped_reads);
+                if total_mapped_reads == 0 {
+                    warn!("No mapped reads found in the BAM index; progress cannot be computed");
+                }
+                total_mapped_reads
+            }
+            Err(rust_htslib::errors::Error::BamInvalidIndex { .. }) => {
+                warn!(
+                    "BAM index not found for {}; run `samtools index {}` to show progress as a percentage and use multiple threads",
+                    bam_file, bam_file
+                );
+                0
+            }
+            Err(error) => return Err(error.into()),
+        }
+    };
+    if threads > 1 && !indexed && !data_loader::is_stdin(bam_file) {
+        warn!("{} is processed with a single thread without an index", bam_file);
+    }
+
+    // Open the BAM file again for processing, reused by the first sequential pass (e.g., for stdin)
+    let mut bam_reader = Some(data_loader::open_bam(bam_file, reference)?);
+
+    // Get reference names (chromosome names), renamed for junction keys
+    let header = bam_reader.as_ref().unwrap().header().to_owned();
+    let reference_names = data_loader::reference_names(bam_file, &header, params.chr_prefix)?;
+
+    // Reads must be sorted by coordinate for progress and for buffered reads to be counted as expected
+    let sort_order = data_loader::sort_order(&header);
+    if sort_order.as_deref() != Some("coordinate") {
+        let message = format!(
+            "{} is not sorted by coordinate ({}); run `samtools sort` first",
+            bam_file,
+            sort_order.map_or("no sort order in @HD".to_string(), |sort_order| format!("@HD SO:{}", sort_order))
+        );
+        if params.strict {
+            return Err(message.into());
+        }
+        warn!("{}", message);
+    }
+
+    // Both passes of two-pass mode are included in the progress
+    let passes = if params.two_pass { 2 } else { 1 };
+    let progress = Progress::new(total_mapped_reads * passes, params.show_progress_bar, params.progress_interval);
+
+    // Writer for junction-spanning reads, using the same header (and reference order) as the input
+    let spanning_header = bam::Header::from_template(&header);
+    let mut spanning_writer = match spanning_bam {
+        Some(path) => Some(writer::create_spanning_writer(path, &spanning_header)?),
+        None => None,
+    };
+
+    // Read map with a header, gzip-compressed unless --no-compress, written in reference order like the spanning BAM
+    let mut read_map = match read_map_path {
+        Some(path) => {
+            let mut read_map = writer::create_output_path(path, compression)?;
+            writeln!(read_map, "ReadName\tBarcode\tJunction")?;
+            Some(read_map)
+        }
+        None => None,
+    };
+
+    // Function to run one pass over the BAM file, processing chromosomes in parallel if threads > 1
+    let mut run_pass = |pass: CountPass,
+                        spanning_writer: Option<&mut bam::Writer>,
+                        read_map: Option<&mut writer::OutputFile>|
+     -> Result<JunctionCounts<JunctionKey, FxBuildHasher>, Box<dyn std::error::Error>> {
+        if threads > 1 && indexed {
+            // Process each chromosome in parallel with its own indexed reader
+            let spanning_bam = if spanning_writer.is_some() { spanning_bam } else { None };
+            let read_map_path = if read_map.is_some() { read_map_path } else { None };
+            // Threads left over by chromosomes running at once are shared for decompression by their readers
+            let decompression_threads = threads / threads.min(reference_names.len()).max(1);
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build()?;
+            let partial_counts = pool.install(|| {
+                (0..reference_names.len() as u32)
+                    .into_par_iter()
+                    // Each worker opens one indexed reader (loading the index and header once) and fetches its chromosomes on it
+                    .map_init(
+                        || -> Result<bam::IndexedReader, rust_htslib::errors::Error> {
+                            let mut reader = data_loader::open_indexed_bam(bam_file, reference)?;
+                            if decompression_threads > 1 {
+                                reader.set_threads(decompression_threads)?;
+                            }
+                            Ok(reader)
+                        },
+                        |reader, tid| -> Result<JunctionCounts<JunctionKey, FxBuildHasher>, Box<dyn std::error::Error + Send + Sync>> {
+                            let reader = reader.as_mut().map_err(|error| format!("Failed to open {}: {}", bam_file, error))?;
+                            reader.fetch(tid)?;
+                            // Write spanning reads to a per-chromosome file to keep them in reference order
+                            let mut partial_writer = match spanning_bam {
+                                Some(path) => Some(writer::create_spanning_writer(
+                                    &writer::partial_path(path, tid, "bam"),
+                                    &spanning_header,
+                                )?),
+                                None => None,
+                            };
+                            let mut partial_read_map = match read_map_path {
+                                Some(path) => Some(writer::create_output_path(&writer::partial_path(path, tid, "reads"), None)?),
+                                None => None,
+                            };
+                            let counts = count_junctions(
+                                reader,
+                                &reference_names,
+                                params,
+                                &progress,
+                                pass,
+                                partial_writer.as_mut(),
+                                partial_read_map.as_mut(),
+                            )?;
+                            if let Some(partial_read_map) = partial_read_map {
+                                partial_read_map.finish()?;
+                            }
+                            Ok(counts)
+                        },
+                    )
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .map_err(|error| error as Box<dyn std::error::Error>)?;
+            let mut counts: JunctionCounts<JunctionKey, FxBuildHasher> = JunctionCounts::default();
+            for partial in partial_counts {
+                counts.merge(partial);
+            }
+            if let (Some(path), Some(writer)) = (spanning_bam, spanning_writer) {
+                let partial_paths: Vec<String> = (0..reference_names.len() as u32)
+                    .map(|tid| writer::partial_path(path, tid, "bam"))
+                    .collect();
+                writer::concat_bams(&partial_paths, writer)?;
+            }
+            if let (Some(path), Some(read_map)) = (read_map_path, read_map) {
+                let partial_paths: Vec<String> = (0..reference_names.len() as u32)
+                    .map(|tid| writer::partial_path(path, tid, "reads"))
+                    .collect();
+                writer::concat_text_files(&partial_paths, read_map)?;
+            }
+            Ok(counts)
+        } else {
+            let mut bam_reader = match bam_reader.take() {
+                Some(bam_reader) => bam_reader,
+                None => data_loader::open_bam(bam_file, reference)?,
+            };
+            // Decompress BGZF blocks with multiple threads (e.g., for stdin or a file without an index)
+            if threads > 1 {
+                bam_reader.set_threads(threads)?;
+            }
+            count_junctions(&mut bam_reader, &reference_names, params, &progress, pass, spanning_writer, read_map)
+                .map_err(|error| error as Box<dyn std::error::Error>)
+        }
+    };
+
+    let counts = if params.two_pass {
+        // Collect supported junctions first so that reads do not need to be buffered
+        let supported = run_pass(CountPass::Collect, None, None)?.supported_junctions;
+        info!("Supported junctions in the first pass: {}", supported.len());
+        run_pass(CountPass::Count(&supported), spanning_writer.as_mut(), read_map.as_mut())?
+    } else {
+        run_pass(CountPass::OnePass, spanning_writer.as_mut(), read_map.as_mut())?
+    };
+    if let Some(read_map) = read_map {
+        read_map.finish()?;
+    }
+    progress.finish();
+
+    // Junction keys are formatted with chromosome names only once counting is done
+    let mut counts = counts.into_named(&reference_names);
+    counts.total_mapped_reads = total_mapped_reads;
+    counts.reference_names = reference_names;
+
+    // Warn once if the NH filter cannot be applied to most reads (e.g., aligners not emitting NH)
+    if counts.missing_nh * 2 > counts.processed_reads {
+        if params.extractor.require_nh {
+            warn!("{} of {} reads lack an NH tag and were skipped", counts.missing_nh, counts.processed_reads);
+        } else {
+            warn!(
+                "{} of {} reads lack an NH tag and bypass --max-loci; use --require-nh to skip them",
+                counts.missing_nh, counts.processed_reads
+            );
+        }
+    }
+
+    // Warn once if most reads lack a cell barcode (e.g., single mode on a bulk BAM or a wrong tag)
+    if counts.missing_barcode * 2 > counts.processed_reads {
+        warn!(
+            "{} of {} reads lack a cell barcode tag ({}) and were not counted; check --cell-barcode-tags or use bulk mode for a bulk BAM",
+            counts.missing_barcode, counts.processed_reads, params.cell_barcode_tags.join(",")
+        );
+    }
+
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_without_total() {
+        // An empty or unindexed BAM has no total, so progress is reported as a read count without dividing by it
+        for interval in [1, 10] {
+            let progress = Progress::new(0, false, interval);
+            for _ in 0..5 {
+                progress.update();
+            }
+            progress.finish();
+            assert_eq!(progress.read_count.load(Ordering::Relaxed), 5);
+        }
+    }
+}
@@ -1,15 +1,153 @@
 // Modules for data loading
-use std::collections::{HashSet};
+use rust_htslib::bam::{self, IndexedReader, Read};
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::{BufRead, BufReader};
+use flate2::read::MultiGzDecoder;
+use log::{debug, warn};
 
-// Function to load the cell barcodes
-pub fn load_cell_barcodes(file_path: Option<&String>) -> Result<HashSet<String>, Box<dyn std::error::Error>> {
+use crate::annotation::GeneIndex;
+use crate::junction::ChrPrefix;
+
+// Function to open a text file for reading, decompressing it if it is gzipped
+pub fn open_text(file_path: &str) -> Result<Box<dyn BufRead>, std::io::Error> {
+    let file = File::open(file_path)?;
+    if file_path.ends_with(".gz") {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(file))))
+    } else {
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+// Function to check whether the alignment file is a CRAM file
+pub fn is_cram(bam_file: &str) -> bool {
+    bam_file.to_lowercase().ends_with(".cram")
+}
+
+// Function to warn if a CRAM file is given without a reference
+pub fn check_cram_reference(bam_file: &str, reference: Option<&String>) {
+    if is_cram(bam_file) && reference.is_none() {
+        warn!("CRAM input without --reference; htslib will look up the reference via REF_PATH/REF_CACHE");
+    }
+}
+
+// Function to check whether the input is read from stdin ("-")
+pub fn is_stdin(bam_file: &str) -> bool {
+    bam_file == "-"
+}
+
+// Function to get the sample name from the BAM/CRAM file name
+pub fn sample_name(bam_file: &str) -> String {
+    std::path::Path::new(bam_file)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_else(|| bam_file.to_string())
+}
+
+// Function to open a BAM/CRAM file for sequential reading
+pub fn open_bam(bam_file: &str, reference: Option<&String>) -> Result<bam::Reader, rust_htslib::errors::Error> {
+    let mut reader = if is_stdin(bam_file) { bam::Reader::from_stdin()? } else { bam::Reader::from_path(bam_file)? };
+    if let Some(fasta) = reference {
+        reader.set_reference(fasta)?;
+    }
+    Ok(reader)
+}
+
+// Function to open an indexed BAM/CRAM file
+pub fn open_indexed_bam(bam_file: &str, reference: Option<&String>) -> Result<IndexedReader, rust_htslib::errors::Error> {
+    let mut reader = IndexedReader::from_path(bam_file)?;
+    if let Some(fasta) = reference {
+        reader.set_reference(fasta)?;
+    }
+    Ok(reader)
+}
+
+// Function to count the total mapped reads from the index
+pub fn count_total_mapped_reads(bam_file: &str, reference: Option<&String>) -> Result<u64, rust_htslib::errors::Error> {
+    let mut bam_index_reader = open_indexed_bam(bam_file, reference)?;
+    let stats = bam_index_reader.index_stats()?;
+    debug!("stats: {:?}", stats);
+    // Sum the mapped reads from all targets
+    Ok(stats.iter().map(|(_, _, mapped, _)| mapped).sum())
+}
+
+// Function to load the read group IDs from the @RG lines of the BAM header
+pub fn load_read_groups(bam_file: &str, reference: Option<&String>) -> Result<HashSet<String>, rust_htslib::errors::Error> {
+    let bam_reader = open_bam(bam_file, reference)?;
+    let header_text = String::from_utf8_lossy(bam_reader.header().as_bytes()).to_string();
+    let read_groups = header_text
+        .lines()
+        .filter(|line| line.starts_with("@RG"))
+        .filter_map(|line| line.split('\t').find_map(|field| field.strip_prefix("ID:")))
+        .map(|id| id.to_string())
+        .collect();
+    Ok(read_groups)
+}
+
+// Function to get the sort order (SO) of the @HD line of the BAM header, if any
+pub fn sort_order(header: &bam::HeaderView) -> Option<String> {
+    let header_text = String::from_utf8_lossy(header.as_bytes()).to_string();
+    header_text
+        .lines()
+        .find(|line| line.starts_with("@HD"))
+        .and_then(|line| line.split('\t').find_map(|field| field.strip_prefix("SO:")))
+        .map(|sort_order| sort_order.to_string())
+}
+
+// Function to get the reference names (chromosome names) of the BAM header, renamed for junction keys
+pub fn reference_names(bam_file: &str, header: &bam::HeaderView, chr_prefix: ChrPrefix) -> Result<Vec<String>, String> {
+    if header.target_count() == 0 {
+        return Err(format!(
+            "{} has no reference sequences (@SQ lines) in its header, e.g., as an unaligned BAM; align the reads first",
+            bam_file
+        ));
+    }
+    Ok(header
+        .target_names()
+        .iter()
+        .map(|name| chr_prefix.apply(&String::from_utf8_lossy(name)))
+        .collect())
+}
+
+// Function to get the reference name of a read, or None for an unmapped read without a reference (tid -1)
+pub fn reference_name<'a>(reference_names: &'a [String], record: &bam::Record) -> Result<Option<&'a String>, String> {
+    let tid = record.tid();
+    if tid < 0 {
+        return Ok(None);
+    }
+    reference_names.get(tid as usize).map(Some).ok_or_else(|| {
+        format!(
+            "Read {} has reference ID {} but the BAM header has only {} reference sequences",
+            String::from_utf8_lossy(record.qname()),
+            tid,
+            reference_names.len()
+        )
+    })
+}
+
+// Function to load chromosome names from a file (one per line) or a comma-separated list
+pub fn load_chrom_list(value: &str) -> Result<HashSet<String>, Box<dyn std::error::Error>> {
+    let chroms = if std::path::Path::new(value).is_file() {
+        let mut chroms = HashSet::new();
+        for line in open_text(value)?.lines() {
+            let chrom = line?.trim().to_string();
+            if !chrom.is_empty() {
+                chroms.insert(chrom);
+            }
+        }
+        chroms
+    } else {
+        value.split(',').map(|chrom| chrom.trim().to_string()).filter(|chrom| !chrom.is_empty()).collect()
+    };
+    Ok(chroms)
+}
+
+// Function to load the union of cell barcodes of the files, one per line of a plain or gzipped file
+pub fn load_cell_barcodes(file_paths: &[String]) -> Result<HashSet<String>, Box<dyn std::error::Error>> {
     let mut barcodes = HashSet::new();
-    if let Some(path) = file_path {
-        let file = File::open(path)?;
-        let reader = BufReader::new(file);
-        for line in reader.lines() {
+    for path in file_paths {
+        // Gzipped whitelists such as barcodes.tsv.gz of CellRanger are decompressed
+        for line in open_text(path)?.lines() {
             let barcode = line?.trim().to_string();
             barcodes.insert(barcode);
         }
@@ -17,6 +155,91 @@ pub fn load_cell_barcodes(file_path: Option<&String>) -> Result<HashSet<String>,
     Ok(barcodes)
 }
 
+// Function to load annotated introns from exon features of a GTF file.
+// Introns are keyed like junctions, "chrom:start-end" with the 1-based last base of the upstream exon
+// as start and the 1-based first base of the downstream exon as end.
+pub fn load_annotated_introns(file_path: &str) -> Result<HashSet<String>, Box<dyn std::error::Error>> {
+    // Collect exons by transcript
+    let mut transcript_exons: HashMap<String, (String, Vec<(i64, i64)>)> = HashMap::new();
+    for line in open_text(file_path)?.lines() {
+        let line = line?;
+        if line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 9 || fields[2] != "exon" {
+            continue;
+        }
+        let transcript_id = match get_gtf_attribute(fields[8], "transcript_id") {
+            Some(id) => id,
+            None => continue,
+        };
+        let start = fields[3].parse::<i64>()?;
+        let end = fields[4].parse::<i64>()?;
+        transcript_exons
+            .entry(transcript_id.to_string())
+            .or_insert_with(|| (fields[0].to_string(), Vec::new()))
+            .1
+            .push((start, end));
+    }
+
+    // Derive introns between consecutive exons of each transcript
+    let mut introns = HashSet::new();
+    for (chrom, mut exons) in transcript_exons.into_values() {
+        exons.sort();
+        for pair in exons.windows(2) {
+            introns.insert(format!("{}:{}-{}", chrom, pair[0].1, pair[1].0));
+        }
+    }
+    Ok(introns)
+}
+
+// Function to load gene spans from exon features of a GTF file
+pub fn load_genes(file_path: &str) -> Result<GeneIndex, Box<dyn std::error::Error>> {
+    // Collect the exon span and name of each gene
+    let mut gene_spans: HashMap<String, (String, i64, i64, String)> = HashMap::new();
+    for line in open_text(file_path)?.lines() {
+        let line = line?;
+        if line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 9 || fields[2] != "exon" {
+            continue;
+        }
+        let gene_id = match get_gtf_attribute(fields[8], "gene_id") {
+            Some(id) => id,
+            None => continue,
+        };
+        let gene_name = get_gtf_attribute(fields[8], "gene_name").unwrap_or(gene_id);
+        let start = fields[3].parse::<i64>()?;
+        let end = fields[4].parse::<i64>()?;
+        let span = gene_spans
+            .entry(gene_id.to_string())
+            .or_insert_with(|| (fields[0].to_string(), start, end, gene_name.to_string()));
+        span.1 = span.1.min(start);
+        span.2 = span.2.max(end);
+    }
+
+    let mut genes: HashMap<String, Vec<(i64, i64, String, String)>> = HashMap::new();
+    for (gene_id, (chrom, start, end, gene_name)) in gene_spans {
+        genes.entry(chrom).or_default().push((start, end, gene_id, gene_name));
+    }
+    Ok(GeneIndex::new(genes))
+}
+
+// Function to get the value of an attribute from the GTF attribute column
+pub fn get_gtf_attribute<'a>(attributes: &'a str, key: &str) -> Option<&'a str> {
+    attributes.split(';').find_map(|attribute| {
+        let (name, value) = attribute.trim().split_once(' ')?;
+        if name == key {
+            Some(value.trim().trim_matches('"'))
+        } else {
+            None
+        }
+    })
+}
+
 // Function to load the intron list
 // pub fn load_introns(file_path: Option<&String>) -> Result<HashSet<(String, i64, i64)>, Box<dyn std::error::Error>> {
 //     let mut introns = HashSet::new();
@@ -39,3 +262,46 @@ pub fn load_cell_barcodes(file_path: Option<&String>) -> Result<HashSet<String>,
 //     }
 //     Ok(introns)
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::header::HeaderRecord;
+
+    // Function to write a BAM file without records, with the given reference sequences in its header
+    fn write_empty_bam(name: &str, targets: &[(&str, u64)]) -> String {
+        let path = std::env::temp_dir().join(format!("tosa_{}_{}.bam", name, std::process::id()));
+        let mut header = bam::Header::new();
+        for (target, length) in targets {
+            header.push_record(HeaderRecord::new(b"SQ").push_tag(b"SN", target).push_tag(b"LN", length));
+        }
+        bam::Writer::from_path(&path, &header, bam::Format::Bam).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn reference_names_without_targets() {
+        let path = write_empty_bam("no_targets", &[]);
+        let reader = open_bam(&path, None).unwrap();
+        let error = reference_names(&path, reader.header(), ChrPrefix::Keep).unwrap_err();
+        assert!(error.contains("has no reference sequences"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reference_names_without_records() {
+        let path = write_empty_bam("no_records", &[("1", 1000)]);
+        let mut reader = open_bam(&path, None).unwrap();
+        let names = reference_names(&path, reader.header(), ChrPrefix::Add).unwrap();
+        assert_eq!(names, vec!["chr1".to_string()]);
+        assert_eq!(reader.records().count(), 0);
+        std::fs::remove_file(path).unwrap();
+
+        // Unmapped reads have no reference, and references missing from the header are an error
+        let mut record = bam::Record::new();
+        record.set_tid(-1);
+        assert_eq!(reference_name(&names, &record), Ok(None));
+        record.set_tid(1);
+        assert!(reference_name(&names, &record).is_err());
+    }
+}
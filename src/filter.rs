@@ -0,0 +1,59 @@
+// Modules for filtering junction counts before writing
+use std::collections::{HashMap, HashSet};
+
+// Function to drop junctions whose total count is below the threshold (bulk mode)
+pub fn filter_totals_by_min_count(junction_totals: &mut HashMap<String, u32>, min_count: u32) -> usize {
+    let before = junction_totals.len();
+    junction_totals.retain(|_, count| *count >= min_count);
+    before - junction_totals.len()
+}
+
+// Function to drop features whose counts summed across barcodes are below the threshold (single mode)
+pub fn filter_features_by_min_count(junction_counts: &mut HashMap<String, HashMap<String, u32>>, min_count: u32) -> usize {
+    let before = junction_counts.len();
+    junction_counts.retain(|_, cell_counts| cell_counts.values().sum::<u32>() >= min_count);
+    before - junction_counts.len()
+}
+
+// Function to drop junctions whose total count is above the cap (bulk mode)
+pub fn filter_totals_by_max_count(junction_totals: &mut HashMap<String, u32>, max_count: u32) -> usize {
+    let before = junction_totals.len();
+    junction_totals.retain(|_, count| *count <= max_count);
+    before - junction_totals.len()
+}
+
+// Function to drop features whose counts summed across barcodes are above the cap (single mode)
+pub fn filter_features_by_max_count(junction_counts: &mut HashMap<String, HashMap<String, u32>>, max_count: u32) -> usize {
+    let before = junction_counts.len();
+    junction_counts.retain(|_, cell_counts| cell_counts.values().sum::<u32>() <= max_count);
+    before - junction_counts.len()
+}
+
+// Function to drop junctions detected in fewer cells than the threshold (single mode)
+pub fn filter_features_by_min_cells(junction_counts: &mut HashMap<String, HashMap<String, u32>>, min_cells: u32) -> usize {
+    let before = junction_counts.len();
+    junction_counts.retain(|_, cell_counts| cell_counts.len() >= min_cells as usize);
+    before - junction_counts.len()
+}
+
+// Function to drop barcodes whose summed junction counts are below the threshold (single mode)
+pub fn filter_barcodes_by_min_count(
+    junction_counts: &mut HashMap<String, HashMap<String, u32>>,
+    cell_barcodes: &mut HashSet<String>,
+    min_count: u32,
+) -> usize {
+    let mut barcode_totals: HashMap<String, u32> = HashMap::new();
+    for cell_counts in junction_counts.values() {
+        for (barcode, count) in cell_counts {
+            *barcode_totals.entry(barcode.clone()).or_insert(0) += count;
+        }
+    }
+    let before = cell_barcodes.len();
+    cell_barcodes.retain(|barcode| barcode_totals.get(barcode).copied().unwrap_or(0) >= min_count);
+    for cell_counts in junction_counts.values_mut() {
+        cell_counts.retain(|barcode, _| cell_barcodes.contains(barcode));
+    }
+    // Drop junctions left without any barcode
+    junction_counts.retain(|_, cell_counts| !cell_counts.is_empty());
+    before - cell_barcodes.len()
+}
@@ -1,38 +1,732 @@
 // Modules for handling junctions
 use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, Hash};
+use rust_htslib::bam::record::{Cigar, Record};
 
-pub fn process_junction(
-    junction_coords: &str,
-    cell_barcode: Option<&String>,
-    junction_counts: &mut HashMap<String, HashMap<String, u32>>,
+use crate::tags;
+
+// Junction spanned by a read, with the coordinates used in junction keys
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Junction {
+    pub tid: i32, // Reference ID of the read
+    pub start: i64, // Last base of the upstream exon (1-based)
+    pub end: i64, // First base of the downstream exon (1-based)
+    pub anchored: bool, // Whether both sides meet the minimum anchor length
+    pub overhang: i64, // Longer of the left and right anchor lengths
+    pub strand: Option<char>, // Strand of the junction ('+' or '-') if known
+    pub intron_index: usize, // Index of the intron among the N operations of the read, including skipped ones
+}
+
+impl Junction {
+    // Function to format the junction key "chrom:start-end", with ":strand" appended if the strand is known
+    pub fn key(&self, chrom: &str) -> String {
+        self.coords().key(chrom)
+    }
+
+    // Function to get the compact key of the junction, formatted to a string only for output
+    pub fn coords(&self) -> JunctionKey {
+        JunctionKey { tid: self.tid, start: self.start, end: self.end, strand: self.strand }
+    }
+}
+
+// Compact junction key used while counting reads, without allocating a string per read
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct JunctionKey {
+    pub tid: i32,
+    pub start: i64,
+    pub end: i64,
+    pub strand: Option<char>,
+}
+
+impl JunctionKey {
+    // Function to format the junction key "chrom:start-end", with ":strand" appended if the strand is known
+    pub fn key(&self, chrom: &str) -> String {
+        match self.strand {
+            Some(strand) => format!("{}:{}-{}:{}", chrom, self.start, self.end, strand),
+            None => format!("{}:{}-{}", chrom, self.start, self.end),
+        }
+    }
+}
+
+// Function to sum the aligned (M, =, X) lengths of a read
+pub fn aligned_length(record: &Record) -> i64 {
+    record
+        .cigar()
+        .iter()
+        .map(|cigar| match cigar {
+            Cigar::Match(l) | Cigar::Equal(l) | Cigar::Diff(l) => *l as i64,
+            _ => 0,
+        })
+        .sum()
+}
+
+// Library strandedness protocol used to infer the strand of junctions from read flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LibraryType {
+    #[default]
+    Unstranded,
+    FrFirstStrand, // Read 1 is antisense to the transcript (e.g., dUTP)
+    FrSecondStrand, // Read 1 is sense to the transcript
+}
+
+impl LibraryType {
+    // Function to infer the transcript strand of a read from its orientation and mate flags
+    pub fn strand(&self, record: &Record) -> Option<char> {
+        // Single-end reads are treated as read 1
+        let is_read2 = record.is_paired() && record.is_last_in_template();
+        let sense = match self {
+            LibraryType::Unstranded => return None,
+            LibraryType::FrFirstStrand => is_read2,
+            LibraryType::FrSecondStrand => !is_read2,
+        };
+        // A sense read aligned forward lies on the plus strand
+        if sense != record.is_reverse() { Some('+') } else { Some('-') }
+    }
+}
+
+// Source of the strand of junctions, tried in order of precedence until one gives a strand
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StrandSource {
+    Library, // Read orientation and mate flags with the library type
+    Xs, // XS tag of aligners such as STAR and HISAT2
+    Ts, // ts tag of minimap2, relative to the read
+    Motif, // Splice motif in the reference, assigned to junctions after counting
+}
+
+// Extractor of junctions from the CIGAR of a read
+#[derive(Debug, Clone)]
+pub struct JunctionExtractor {
+    pub min_anchor_length: i64,
+    pub min_left_anchor_length: Option<i64>, // Overrides min_anchor_length for the upstream side
+    pub min_right_anchor_length: Option<i64>, // Overrides min_anchor_length for the downstream side
+    pub min_intron_length: i64,
+    pub max_intron_length: i64,
+    pub max_loci: u32,
+    pub require_nh: bool, // Whether reads lacking an NH tag fail the max_loci filter
+    pub min_aligned_length: i64, // Minimum sum of the aligned (M, =, X) lengths of a read
+    pub min_boundary_qual: u8, // Minimum base quality of the read bases flanking each junction
+    pub min_insert_size: Option<i64>, // Minimum absolute insert size (TLEN) of properly paired reads
+    pub max_insert_size: Option<i64>, // Maximum absolute insert size (TLEN) of properly paired reads
+    pub library_type: LibraryType,
+    pub strand_sources: Vec<StrandSource>, // Sources of the junction strand in order of precedence
+}
+
+impl Default for JunctionExtractor {
+    fn default() -> Self {
+        JunctionExtractor {
+            min_anchor_length: 8,
+            min_left_anchor_length: None,
+            min_right_anchor_length: None,
+            min_intron_length: 70,
+            max_intron_length: 500000,
+            max_loci: 1,
+            require_nh: false,
+            min_aligned_length: 0,
+            min_boundary_qual: 0,
+            min_insert_size: None,
+            max_insert_size: None,
+            library_type: LibraryType::Unstranded,
+            strand_sources: vec![StrandSource::Library],
+        }
+    }
+}
+
+// Builder for a JunctionExtractor, starting from the command-line defaults
+#[derive(Debug, Clone, Default)]
+pub struct JunctionExtractorBuilder {
+    extractor: JunctionExtractor,
+}
+
+impl JunctionExtractorBuilder {
+    pub fn min_anchor_length(mut self, min_anchor_length: i64) -> Self {
+        self.extractor.min_anchor_length = min_anchor_length;
+        self
+    }
+
+    pub fn min_left_anchor_length(mut self, min_left_anchor_length: Option<i64>) -> Self {
+        self.extractor.min_left_anchor_length = min_left_anchor_length;
+        self
+    }
+
+    pub fn min_right_anchor_length(mut self, min_right_anchor_length: Option<i64>) -> Self {
+        self.extractor.min_right_anchor_length = min_right_anchor_length;
+        self
+    }
+
+    pub fn min_intron_length(mut self, min_intron_length: i64) -> Self {
+        self.extractor.min_intron_length = min_intron_length;
+        self
+    }
+
+    pub fn max_intron_length(mut self, max_intron_length: i64) -> Self {
+        self.extractor.max_intron_length = max_intron_length;
+        self
+    }
+
+    pub fn max_loci(mut self, max_loci: u32) -> Self {
+        self.extractor.max_loci = max_loci;
+        self
+    }
+
+    pub fn require_nh(mut self, require_nh: bool) -> Self {
+        self.extractor.require_nh = require_nh;
+        self
+    }
+
+    pub fn min_aligned_length(mut self, min_aligned_length: i64) -> Self {
+        self.extractor.min_aligned_length = min_aligned_length;
+        self
+    }
+
+    pub fn min_boundary_qual(mut self, min_boundary_qual: u8) -> Self {
+        self.extractor.min_boundary_qual = min_boundary_qual;
+        self
+    }
+
+    pub fn min_insert_size(mut self, min_insert_size: Option<i64>) -> Self {
+        self.extractor.min_insert_size = min_insert_size;
+        self
+    }
+
+    pub fn max_insert_size(mut self, max_insert_size: Option<i64>) -> Self {
+        self.extractor.max_insert_size = max_insert_size;
+        self
+    }
+
+    pub fn library_type(mut self, library_type: LibraryType) -> Self {
+        self.extractor.library_type = library_type;
+        self
+    }
+
+    pub fn strand_sources(mut self, strand_sources: Vec<StrandSource>) -> Self {
+        self.extractor.strand_sources = strand_sources;
+        self
+    }
+
+    pub fn build(self) -> JunctionExtractor {
+        self.extractor
+    }
+}
+
+impl JunctionExtractor {
+    pub fn builder() -> JunctionExtractorBuilder {
+        JunctionExtractorBuilder::default()
+    }
+
+    // Function to check whether the NH tag of a read is within max_loci, failing reads without NH if required
+    pub fn passes_max_loci(&self, record: &Record) -> bool {
+        match tags::get_nh(record) {
+            Some(nh) => nh <= self.max_loci as u64,
+            None => !self.require_nh,
+        }
+    }
+
+    // Function to check whether the aligned (M, =, X) length of a read reaches min_aligned_length
+    pub fn passes_min_aligned_length(&self, record: &Record) -> bool {
+        self.min_aligned_length <= 0 || aligned_length(record) >= self.min_aligned_length
+    }
+
+    // Function to check whether a read has introns (N operations) but none in the intron length range
+    pub fn lacks_intron_in_range(&self, record: &Record) -> bool {
+        let mut has_intron = false;
+        for cigar in record.cigar().iter() {
+            if let Cigar::RefSkip(len) = cigar {
+                let intron_length = *len as i64;
+                if intron_length >= self.min_intron_length && intron_length <= self.max_intron_length {
+                    return false;
+                }
+                has_intron = true;
+            }
+        }
+        has_intron
+    }
+
+    // Function to check whether the absolute insert size of a properly paired read is within the range;
+    // other reads (e.g., single-end reads and singletons) always pass
+    pub fn passes_insert_size(&self, record: &Record) -> bool {
+        if !record.is_paired() || !record.is_proper_pair() {
+            return true;
+        }
+        let insert_size = record.insert_size().abs();
+        self.min_insert_size.is_none_or(|min| insert_size >= min) && self.max_insert_size.is_none_or(|max| insert_size <= max)
+    }
+
+    // Function to get the strand of the junctions of a read from the first strand source giving one
+    // The splice motif gives no strand here, as it is only known for junctions after counting
+    pub fn strand(&self, record: &Record) -> Option<char> {
+        self.strand_sources.iter().find_map(|source| match source {
+            StrandSource::Library => self.library_type.strand(record),
+            StrandSource::Xs => tags::get_xs(record),
+            StrandSource::Ts => tags::get_ts(record),
+            StrandSource::Motif => None,
+        })
+    }
+
+    // Function to extract the junctions in the intron length range spanned by a read
+    // Junctions lacking sufficient anchors are returned with anchored set to false
+    pub fn extract(&self, record: &Record) -> Vec<Junction> {
+        let mut junctions = Vec::new();
+        if !self.passes_max_loci(record) {
+            return junctions;
+        }
+
+        let mut current_pos = record.pos(); // Start of the alignment
+        let mut query_pos: usize = 0; // Position in the read sequence, tracked to check the quality at junction boundaries
+        let qual = record.qual();
+        let strand = self.strand(record);
+        let min_left_anchor_length = self.min_left_anchor_length.unwrap_or(self.min_anchor_length);
+        let min_right_anchor_length = self.min_right_anchor_length.unwrap_or(self.min_anchor_length);
+        let mut aligned_length = 0; // Sum of the aligned (M, =, X) lengths, accumulated in the same pass
+        let cigar_vec = record.cigar(); // Create a longer-lived binding for the cigar data
+        let cigars: Vec<_> = cigar_vec.iter().collect();
+        let mut intron_count = 0;
+        for i in 0..cigars.len() {
+            if let Cigar::RefSkip(len) = cigars[i] {
+                let intron_index = intron_count;
+                intron_count += 1;
+                // Check intron length constraints
+                let intron_length = *len as i64;
+                if intron_length < self.min_intron_length || intron_length > self.max_intron_length {
+                    // Skip junctions outside the specified intron length range
+                    current_pos += intron_length;
+                    continue;
+                }
+
+                // Skip junctions whose flanking read bases have low quality (missing qualities, 0xff, pass)
+                if self.min_boundary_qual > 0 {
+                    let flanking_quals = [query_pos.checked_sub(1).and_then(|pos| qual.get(pos)), qual.get(query_pos)];
+                    if flanking_quals.iter().any(|q| q.is_some_and(|&q| q != 0xff && q < self.min_boundary_qual)) {
+                        current_pos += intron_length;
+                        continue;
+                    }
+                }
+
+                // Calculate left anchor length by accumulating lengths before the RefSkip
+                // (in full rather than up to the threshold, to report the overhang)
+                let mut left_anchor_length = 0;
+                let mut j = i; // Start from the current CIGAR index
+                while j > 0 {
+                    j -= 1; // Move to the previous CIGAR element
+                    match cigars[j] {
+                        Cigar::Match(l) | Cigar::Equal(l) | Cigar::Diff(l) => left_anchor_length += *l as i64,
+                        Cigar::RefSkip(_) => continue, // Skip RefSkip and keep checking alignment elements
+                        Cigar::HardClip(_) | Cigar::Pad(_) => continue, // Hard clips and padding do not consume the reference or stop the anchor
+                        _ => break, // Stop accumulating for other operations
+                    }
+                }
+                let has_left_anchor = left_anchor_length >= min_left_anchor_length;
+
+                // Calculate right anchor length by accumulating lengths after the RefSkip
+                let mut right_anchor_length = 0;
+                let mut k = i + 1; // Start from the next CIGAR index
+                while k < cigars.len() {
+                    match cigars[k] {
+                        Cigar::Match(r) | Cigar::Equal(r) | Cigar::Diff(r) => right_anchor_length += *r as i64,
+                        Cigar::RefSkip(_) => { k += 1; continue; } // Skip RefSkip and keep checking alignment elements
+                        Cigar::HardClip(_) | Cigar::Pad(_) => { k += 1; continue; } // Hard clips and padding do not consume the reference or stop the anchor
+                        _ => break, // Stop accumulating for other operations
+                    }
+                    k += 1; // Move to the next CIGAR element
+                }
+                let has_right_anchor = right_anchor_length >= min_right_anchor_length;
+
+                junctions.push(Junction {
+                    tid: record.tid(),
+                    start: current_pos,
+                    end: current_pos + intron_length + 1,
+                    anchored: has_left_anchor && has_right_anchor,
+                    overhang: left_anchor_length.max(right_anchor_length),
+                    strand,
+                    intron_index,
+                });
+                current_pos += intron_length;
+            } else if let Cigar::SoftClip(len) = cigars[i] {
+                query_pos += *len as usize;
+            } else if let Cigar::HardClip(_len) = cigars[i] {
+                continue;
+            } else {
+                current_pos += match cigars[i] {
+                    Cigar::Match(l) | Cigar::Equal(l) | Cigar::Diff(l) | Cigar::Del(l) => *l as i64, // Insertions and padding do not consume the reference
+                    _ => 0,
+                };
+                if let Cigar::Match(l) | Cigar::Equal(l) | Cigar::Diff(l) = cigars[i] {
+                    aligned_length += *l as i64;
+                }
+                if let Cigar::Match(l) | Cigar::Equal(l) | Cigar::Diff(l) | Cigar::Ins(l) = cigars[i] {
+                    query_pos += *l as usize;
+                }
+            }
+        }
+
+        // Short alignments containing a junction are skipped as low-confidence
+        if aligned_length < self.min_aligned_length {
+            junctions.clear();
+        }
+        junctions
+    }
+}
+
+// Function to get the pairs of anchored junctions of a read flanking an exon shorter than max_length (microexons).
+// Only junctions of consecutive N operations are paired, so an exon never spans an intron that was skipped
+// (e.g., outside the intron length range).
+pub fn microexons(junctions: &[Junction], max_length: i64) -> Vec<(&Junction, &Junction)> {
+    junctions
+        .windows(2)
+        .map(|pair| (&pair[0], &pair[1]))
+        // The exon spans from the end of the upstream junction to the start of the downstream one, inclusive
+        .filter(|(upstream, downstream)| {
+            upstream.anchored
+                && downstream.anchored
+                && downstream.intron_index == upstream.intron_index + 1
+                && downstream.start - upstream.end < max_length
+        })
+        .collect()
+}
+
+// Conversion of chromosome names between Ensembl ("1") and UCSC ("chr1") styles
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChrPrefix {
+    #[default]
+    Keep,
+    Add,
+    Strip,
+}
+
+impl ChrPrefix {
+    // Function to rename a chromosome, leaving names that already have or lack the prefix untouched
+    pub fn apply(&self, chrom: &str) -> String {
+        match self {
+            ChrPrefix::Add if !chrom.starts_with("chr") => format!("chr{}", chrom),
+            ChrPrefix::Strip => chrom.strip_prefix("chr").unwrap_or(chrom).to_string(),
+            _ => chrom.to_string(),
+        }
+    }
+}
+
+// Convention of the junction coordinates written to output files. Junctions are keyed internally by the
+// flanking exon bases (1-based last base of the upstream exon and first base of the downstream exon).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CoordBase {
+    #[default]
+    Exon, // Flanking exon bases, 1-based
+    One, // First and last intron bases, 1-based (e.g., STAR SJ.out.tab)
+    Zero, // Intron start 0-based and end exclusive (e.g., BED)
+}
+
+impl CoordBase {
+    // Function to convert an internal junction key to this convention, keeping the strand
+    pub fn apply(&self, junction_coords: &str) -> String {
+        let (start, end) = match (self, parse_junction(junction_coords)) {
+            (CoordBase::One, Some((_, start, end))) => (start + 1, end - 1),
+            (CoordBase::Zero, Some((_, start, end))) => (start, end - 1),
+            _ => return junction_coords.to_string(),
+        };
+        let (coords, strand) = split_strand(junction_coords);
+        let (chrom, _) = coords.rsplit_once(':').unwrap_or((coords, ""));
+        match strand {
+            Some(strand) => format!("{}:{}-{}:{}", chrom, start, end, strand),
+            None => format!("{}:{}-{}", chrom, start, end),
+        }
+    }
+
+    // Function to convert the junction keys of counts to this convention
+    pub fn rekey<T>(&self, counts: HashMap<String, T>) -> HashMap<String, T> {
+        if *self == CoordBase::Exon {
+            return counts;
+        }
+        counts.into_iter().map(|(junction_coords, value)| (self.apply(&junction_coords), value)).collect()
+    }
+}
+
+// Function to split a junction key into "chrom:start-end" and its strand, if any
+pub fn split_strand(junction_coords: &str) -> (&str, Option<&str>) {
+    match junction_coords.rsplit_once(':') {
+        Some((coords, strand)) if strand == "+" || strand == "-" => (coords, Some(strand)),
+        _ => (junction_coords, None),
+    }
+}
+
+// Function to parse a junction key "chrom:start-end[:strand]" into its coordinates
+pub fn parse_junction(junction_coords: &str) -> Option<(&str, i64, i64)> {
+    let (coords, _strand) = split_strand(junction_coords);
+    let (chrom, range) = coords.rsplit_once(':')?;
+    let (start, end) = range.split_once('-')?;
+    Some((chrom, start.parse().ok()?, end.parse().ok()?))
+}
+
+// Function to sort junctions by the reference order of their chromosomes, then numerically by start, end and strand
+// Chromosomes missing from the reference names are placed last
+pub fn sort_by_position<'a>(junctions: impl Iterator<Item = &'a String>, reference_names: &[String]) -> Vec<&'a String> {
+    let reference_order: HashMap<&str, usize> =
+        reference_names.iter().enumerate().map(|(index, name)| (name.as_str(), index)).collect();
+    let mut junctions: Vec<&String> = junctions.collect();
+    junctions.sort_by_cached_key(|junction_coords| {
+        let (_, strand) = split_strand(junction_coords);
+        let (chrom, start, end) = parse_junction(junction_coords).unwrap_or(("", 0, 0));
+        (
+            reference_order.get(chrom).copied().unwrap_or(usize::MAX),
+            chrom.to_string(),
+            start,
+            end,
+            strand.map(|strand| strand.to_string()),
+        )
+    });
+    junctions
+}
+
+// Function to merge junctions whose donor and acceptor are each within radius bp of a higher-count junction
+// on the same chromosome and strand, summing their counts onto the representative junction
+pub fn collapse_junctions(
     junction_totals: &mut HashMap<String, u32>,
-    processed_reads: &mut HashMap<String, HashSet<String>>,
-    read_name: &str,                                        // Read name for tracking
+    junction_counts: &mut HashMap<String, HashMap<String, u32>>,
+    other_totals: &mut [&mut HashMap<String, u32>], // Other counts by junction summed onto representatives (e.g., multimapped reads)
+    junction_positions: &mut HashMap<String, HashMap<i64, u32>>,
+    max_overhangs: &mut HashMap<String, i64>,
+    radius: i64,
+) -> usize {
+    // Total count of each junction, summed over barcodes if only counts by barcode are available
+    let mut weights: HashMap<String, u32> = junction_totals.clone();
+    for (junction_coords, cell_counts) in junction_counts.iter() {
+        weights
+            .entry(junction_coords.clone())
+            .or_insert_with(|| cell_counts.values().sum());
+    }
+
+    // Group junctions by chromosome and strand, sorted by start
+    let mut groups: HashMap<String, Vec<(i64, i64, String)>> = HashMap::new();
+    for junction_coords in weights.keys() {
+        let (_, strand) = split_strand(junction_coords);
+        if let Some((chrom, start, end)) = parse_junction(junction_coords) {
+            groups
+                .entry(format!("{}:{}", chrom, strand.unwrap_or(".")))
+                .or_default()
+                .push((start, end, junction_coords.clone()));
+        }
+    }
+
+    // Assign each junction to the highest-count junction within the radius, in order of decreasing count
+    let mut representatives: HashMap<String, String> = HashMap::new();
+    for group in groups.values_mut() {
+        group.sort();
+        let mut order: Vec<usize> = (0..group.len()).collect();
+        order.sort_by(|&a, &b| weights[&group[b].2].cmp(&weights[&group[a].2]).then(a.cmp(&b)));
+        let mut assigned = vec![false; group.len()];
+        for i in order {
+            if assigned[i] {
+                continue;
+            }
+            let (rep_start, rep_end, rep_coords) = &group[i];
+            let first = group.partition_point(|junction| junction.0 < rep_start - radius);
+            let last = group.partition_point(|junction| junction.0 <= rep_start + radius);
+            for j in first..last {
+                if !assigned[j] && (group[j].1 - rep_end).abs() <= radius {
+                    assigned[j] = true;
+                    if j != i {
+                        representatives.insert(group[j].2.clone(), rep_coords.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    // Move the counts of merged junctions onto their representatives
+    for (junction_coords, rep_coords) in &representatives {
+        if let Some(count) = junction_totals.remove(junction_coords) {
+            *junction_totals.entry(rep_coords.clone()).or_insert(0) += count;
+        }
+        for totals in other_totals.iter_mut() {
+            if let Some(count) = totals.remove(junction_coords) {
+                *totals.entry(rep_coords.clone()).or_insert(0) += count;
+            }
+        }
+        if let Some(positions) = junction_positions.remove(junction_coords) {
+            let rep_entry = junction_positions.entry(rep_coords.clone()).or_default();
+            for (pos, count) in positions {
+                *rep_entry.entry(pos).or_insert(0) += count;
+            }
+        }
+        if let Some(overhang) = max_overhangs.remove(junction_coords) {
+            let rep_overhang = max_overhangs.entry(rep_coords.clone()).or_insert(0);
+            *rep_overhang = (*rep_overhang).max(overhang);
+        }
+        if let Some(cell_counts) = junction_counts.remove(junction_coords) {
+            let rep_entry = junction_counts.entry(rep_coords.clone()).or_default();
+            for (barcode, count) in cell_counts {
+                *rep_entry.entry(barcode).or_insert(0) += count;
+            }
+        }
+    }
+    representatives.len()
+}
+
+// Function to compute the Shannon entropy of the alignment starts of the reads supporting a junction,
+// normalized by its maximum (all reads starting at distinct positions) to range from 0 (one position) to 1
+pub fn position_entropy(positions: &HashMap<i64, u32>) -> f64 {
+    let total: u32 = positions.values().sum();
+    if total <= 1 {
+        return 0.0;
+    }
+    let entropy: f64 = positions
+        .values()
+        .map(|&count| {
+            let p = count as f64 / total as f64;
+            -p * p.ln()
+        })
+        .sum();
+    entropy / (total as f64).ln()
+}
+
+// Function to sum junction counts by donor and acceptor splice site, keyed by "chrom:position" with ":strand"
+// appended if the strand is known. The donor is the left site and the acceptor the right site, except on the
+// minus strand, where the roles are swapped.
+pub fn count_splice_sites(junction_totals: &HashMap<String, u32>) -> (HashMap<String, u32>, HashMap<String, u32>) {
+    let mut donor_totals: HashMap<String, u32> = HashMap::new();
+    let mut acceptor_totals: HashMap<String, u32> = HashMap::new();
+    for (junction_coords, count) in junction_totals {
+        if let Some((chrom, start, end)) = parse_junction(junction_coords) {
+            let (_, strand) = split_strand(junction_coords);
+            let (donor, acceptor) = if strand == Some("-") { (end, start) } else { (start, end) };
+            let site = |position: i64| match strand {
+                Some(strand) => format!("{}:{}:{}", chrom, position, strand),
+                None => format!("{}:{}", chrom, position),
+            };
+            *donor_totals.entry(site(donor)).or_insert(0) += count;
+            *acceptor_totals.entry(site(acceptor)).or_insert(0) += count;
+        }
+    }
+    (donor_totals, acceptor_totals)
+}
+
+pub fn process_junction<K: Hash + Eq + Clone, S: BuildHasher + Default>(
+    junction_coords: &K,
+    cell_barcode: Option<&String>,
+    junction_counts: &mut HashMap<K, HashMap<String, u32, S>, S>,
+    junction_totals: &mut HashMap<K, u32, S>,
+    processed_reads: &mut HashMap<K, HashSet<String, S>, S>,
+    read_key: &str,                                         // Read name or CB:UMI key for tracking
     mode: &str,
-) {
+) -> bool {
     // Check if the read was already processed for this junction
     if let Some(reads) = processed_reads.get_mut(junction_coords) {
-        if reads.contains(read_name) {
-            return; // Skip counting
+        if reads.contains(read_key) {
+            return false; // Skip counting
         }
-        reads.insert(read_name.to_string());
+        reads.insert(read_key.to_string());
     } else {
-        let mut reads_set = HashSet::new();
-        reads_set.insert(read_name.to_string());
-        processed_reads.insert(junction_coords.to_string(), reads_set);
+        let mut reads_set = HashSet::default();
+        reads_set.insert(read_key.to_string());
+        processed_reads.insert(junction_coords.clone(), reads_set);
     }
 
     // Count the read for the junction
     if mode == "single" {
         if let Some(cb_str) = cell_barcode {
             let junction_entry = junction_counts
-                .entry(junction_coords.to_string())
-                .or_insert_with(HashMap::new);
+                .entry(junction_coords.clone())
+                .or_default();
             *junction_entry.entry(cb_str.clone()).or_insert(0) += 1;
+            return true;
         }
+        false
     } else {
         *junction_totals
-            .entry(junction_coords.to_string())
+            .entry(junction_coords.clone())
             .or_insert(0) += 1;
+        // Also count by read group if reads are split by RG
+        if let Some(rg_str) = cell_barcode {
+            let junction_entry = junction_counts
+                .entry(junction_coords.clone())
+                .or_default();
+            *junction_entry.entry(rg_str.clone()).or_insert(0) += 1;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::CigarString;
+
+    // Function to build a read on the first reference from a CIGAR string and a 0-based position
+    fn record(cigar: &str, pos: i64) -> Record {
+        let cigar = CigarString::try_from(cigar).unwrap();
+        let query_length: usize = cigar
+            .iter()
+            .map(|cigar| match cigar {
+                Cigar::Match(l) | Cigar::Ins(l) | Cigar::SoftClip(l) | Cigar::Equal(l) | Cigar::Diff(l) => *l as usize,
+                _ => 0,
+            })
+            .sum();
+        let mut record = Record::new();
+        record.set(b"read", Some(&cigar), &vec![b'A'; query_length], &vec![30; query_length]);
+        record.set_tid(0);
+        record.set_pos(pos);
+        record
+    }
+
+    #[test]
+    fn extract_after_insertion() {
+        // The insertion does not consume the reference, so the junction starts after the 15 aligned bases
+        let junctions = JunctionExtractor::default().extract(&record("10M2I5M100N10M", 99));
+        assert_eq!(junctions.len(), 1);
+        assert_eq!((junctions[0].start, junctions[0].end), (114, 215));
+        assert_eq!(junctions[0].overhang, 10);
+    }
+
+    #[test]
+    fn extract_with_hard_clip() {
+        // Hard clips (e.g., of supplementary alignments) consume neither the read nor the reference
+        let junctions = JunctionExtractor::default().extract(&record("5H10M100N10M", 99));
+        assert_eq!(junctions.len(), 1);
+        assert_eq!((junctions[0].start, junctions[0].end), (109, 210));
+        assert!(junctions[0].anchored);
+        assert_eq!(junctions[0].overhang, 10);
+
+        // A hard clip next to a short anchor does not extend it
+        let junctions = JunctionExtractor::default().extract(&record("5H5M100N10M5H", 99));
+        assert_eq!((junctions[0].start, junctions[0].end), (104, 205));
+        assert!(!junctions[0].anchored);
+        assert_eq!(junctions[0].overhang, 10);
+    }
+
+    #[test]
+    fn extract_with_sequence_match_and_mismatch() {
+        // Sequence matches (=) and mismatches (X) count as aligned bases like M
+        let junctions = JunctionExtractor::default().extract(&record("5=1X4=100N3=1X6=200N10M", 99));
+        assert_eq!(junctions.len(), 2);
+        assert_eq!((junctions[0].start, junctions[0].end), (109, 210));
+        assert_eq!((junctions[1].start, junctions[1].end), (219, 420));
+        assert!(junctions.iter().all(|junction| junction.anchored));
+    }
+
+    #[test]
+    fn microexons_of_consecutive_introns() {
+        let extractor = JunctionExtractor::default();
+        let junctions = extractor.extract(&record("10M100N5M100N10M", 99));
+        let pairs = microexons(&junctions, 25);
+        assert_eq!(pairs.len(), 1);
+        assert_eq!((pairs[0].0.end, pairs[0].1.start), (210, 214));
+
+        // The 20 bp N is outside the intron length range, so the exons around it are not a microexon
+        let junctions = extractor.extract(&record("10M100N5M20N5M100N10M", 99));
+        assert_eq!(junctions.len(), 2);
+        assert!(microexons(&junctions, 50).is_empty());
+    }
+
+    #[test]
+    fn coord_base_apply() {
+        // Read at 1-based position 100 with the intron at 1-based 120-219
+        let junctions = JunctionExtractor::default().extract(&record("20M100N20M", 99));
+        let key = junctions[0].key("chr1");
+        assert_eq!(CoordBase::Exon.apply(&key), "chr1:119-220"); // Flanking exon bases
+        assert_eq!(CoordBase::One.apply(&key), "chr1:120-219"); // STAR SJ.out.tab intron
+        assert_eq!(CoordBase::Zero.apply(&key), "chr1:119-219"); // BED intron
+
+        // The strand is kept
+        let junctions = JunctionExtractor::builder().library_type(LibraryType::FrSecondStrand).build().extract(&record("20M100N20M", 99));
+        let key = junctions[0].key("chr1");
+        assert_eq!(CoordBase::One.apply(&key), "chr1:120-219:+");
+        assert_eq!(CoordBase::Zero.apply(&key), "chr1:119-219:+");
     }
 }
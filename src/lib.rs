@@ -0,0 +1,14 @@
+// Library API of Tosa for extracting and counting junction reads
+pub mod annotation;
+pub mod boundary;
+pub mod counter;
+pub mod data_loader;
+pub mod filter;
+pub mod junction;
+pub mod merge;
+pub mod sequence;
+pub mod stats;
+pub mod tags;
+pub mod writer;
+
+pub use junction::{ChrPrefix, CoordBase, Junction, JunctionExtractor, JunctionExtractorBuilder, LibraryType, StrandSource};
@@ -1,105 +1,585 @@
-use clap::{Arg, Command};
-use rust_htslib::bam::{self, Read};
-use rust_htslib::bam::IndexedReader;
-use rust_htslib::bam::record::{Aux, Cigar};
+use clap::parser::ValueSource;
+use clap::{Arg, ArgMatches, Command};
 use std::collections::{HashMap, HashSet};
-use std::fs::File;
-use std::io::{Write};
-use log::{info, debug, LevelFilter};
-use env_logger;
+use std::io::{IsTerminal, Write};
+use log::{info, debug, warn, LevelFilter};
 use itertools::Itertools;
-use flate2::write::GzEncoder;
 use flate2::Compression;
 
-mod data_loader;
-mod junction;
+use tosa::{boundary, counter, data_loader, filter, junction, merge, sequence, stats, tags, writer, ChrPrefix, CoordBase, JunctionExtractor, LibraryType, StrandSource};
+
+const VERSION: &str = "0.3.0";
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Set up command-line arguments using clap
     let matches = Command::new("tosa")
-        .version("0.3.0")
+        .version(VERSION)
         .author("NaotoKubota")
         .about("Extract junction reads from RNA-seq/scRNA-seq bam files")
-        .arg(Arg::new("mode")
-            .required(true)
-            .value_parser(["bulk", "single"])
-            .help("Mode of operation: 'bulk' or 'single'"))
-        .arg(Arg::new("bam_file")
-            .required(true)
-            .help("Path to the BAM file"))
-        .arg(Arg::new("output_dir")
-            .required(true)
-            .help("Output directory for the output files"))
-        .arg(Arg::new("anchor_length")
-            .short('a')
-            .long("anchor-length")
-            .default_value("8")
-            .value_parser(clap::value_parser!(i64))
-            .help("Minimum anchor length for both sides of junctions"))
-        .arg(Arg::new("min_intron_length")
-            .short('m')
-            .long("min-intron-length")
-            .default_value("70")
-            .value_parser(clap::value_parser!(i64))
-            .help("Minimum intron length for junctions"))
-        .arg(Arg::new("max_intron_length")
-            .short('M')
-            .long("max-intron-length")
-            .default_value("500000")
-            .value_parser(clap::value_parser!(i64))
-            .help("Maximum intron length for junctions"))
-        .arg(Arg::new("max_loci")
-            .short('l')
-            .long("max-loci")
-            .default_value("1")
-            .value_parser(clap::value_parser!(u32))
-            .help("Maximum number of loci the read maps to"))
-        .arg(Arg::new("cell_barcode_file")
-            .short('c')
-            .long("cell-barcodes")
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .subcommand(Command::new("bulk")
+            .about("Count junction reads of bulk RNA-seq BAM files")
+            .arg(Arg::new("bam_file")
+                .required(true)
+                .num_args(1..)
+                .help("Path to the BAM or CRAM file(s) ('-' for stdin); multiple files are counted as samples"))
+            .arg(Arg::new("output_dir")
+                .required(true)
+                .help("Output directory for the output files ('-' for stdout)"))
+            .args(count_args())
+            .arg(Arg::new("intron_retention")
+                .long("intron-retention")
+                .action(clap::ArgAction::SetTrue)
+                .help("Count reads spanning the donor or acceptor of each junction without splicing"))
+            .arg(Arg::new("cpm")
+                .long("cpm")
+                .action(clap::ArgAction::SetTrue)
+                .help("Add counts-per-million (CPM) column to the bulk output"))
+            .arg(Arg::new("splice_site_seq")
+                .long("splice-site-seq")
+                .action(clap::ArgAction::SetTrue)
+                .requires("reference")
+                .help("Add the donor and acceptor dinucleotides of each junction from the --reference FASTA as columns"))
+            .arg(Arg::new("splice_motif")
+                .long("splice-motif")
+                .action(clap::ArgAction::SetTrue)
+                .requires("reference")
+                .help("Add the intron motif of each junction from the --reference FASTA as a column, coded as in STAR (0: non-canonical, 1: GT/AG, 2: CT/AC, 3: GC/AG, 4: CT/GC, 5: AT/AC, 6: GT/AT)"))
+            .arg(Arg::new("splice_site_flank")
+                .long("splice-site-flank")
+                .default_value("0")
+                .value_parser(clap::value_parser!(i64).range(0..))
+                .help("Number of flanking exon bases added to the splice-site sequences of --splice-site-seq"))
+            .arg(Arg::new("split_output")
+                .long("split-output")
+                .action(clap::ArgAction::SetTrue)
+                .help("Write junctions to one junction.<chrom>.tsv file per chromosome with junctions instead of a single file"))
+            .arg(Arg::new("split_by_rg")
+                .long("split-by-rg")
+                .action(clap::ArgAction::SetTrue)
+                .help("Count junctions by read group (RG) into a junction-by-read-group matrix")))
+        .subcommand(Command::new("single")
+            .about("Count junction reads by cell barcode of single-cell RNA-seq BAM files")
+            .arg(Arg::new("bam_file")
+                .required(true)
+                .help("Path to the BAM or CRAM file ('-' for stdin)"))
+            .arg(Arg::new("output_dir")
+                .required(true)
+                .help("Output directory for the output files"))
+            .args(count_args())
+            .arg(Arg::new("min_cells")
+                .long("min-cells")
+                .value_parser(clap::value_parser!(u32))
+                .help("Minimum number of cells in which a junction is detected"))
+            .arg(Arg::new("min_barcode_count")
+                .long("min-barcode-count")
+                .value_parser(clap::value_parser!(u32))
+                .help("Minimum total junction count for a barcode to be kept"))
+            .arg(Arg::new("cell_barcode_file")
+                .short('c')
+                .long("cell-barcodes")
+                .action(clap::ArgAction::Append)
+                .value_delimiter(',')
+                .value_parser(clap::value_parser!(String))
+                .help("Optional file(s) specifying cell barcodes of interest (repeatable or comma-separated); barcodes of all files are used"))
+            .arg(Arg::new("cell_barcode_tags")
+                .long("cell-barcode-tags")
+                .visible_alias("cell-barcode-tag")
+                .default_value("CB")
+                .value_delimiter(',')
+                .value_parser(clap::value_parser!(String))
+                .help("Tags for cell barcodes in order of precedence, comma-separated; the first tag present in a read is used (e.g., CB,CR)"))
+            .arg(Arg::new("barcode_from_name")
+                .long("barcode-from-name")
+                .value_parser(clap::value_parser!(String))
+                .help("Take cell barcodes of reads lacking the barcode tags from their names, after the last occurrence of a delimiter (e.g., '_') or in the first capture group of a regex (e.g., ':([ACGT]+)$')"))
+            .arg(Arg::new("strip_barcode_suffix")
+                .long("strip-barcode-suffix")
+                .action(clap::ArgAction::SetTrue)
+                .help("Trim the suffix from the last '-' of cell barcodes (e.g., AAAC-1 -> AAAC)"))
+            .arg(Arg::new("umi_tag")
+                .long("umi-tag")
+                .default_value("UB")
+                .value_parser(clap::value_parser!(String))
+                .help("Tag for UMIs used to deduplicate reads"))
+            .arg(Arg::new("dropped_barcodes")
+                .long("dropped-barcodes")
+                .requires("cell_barcode_file")
+                .value_parser(clap::value_parser!(String))
+                .help("Optional TSV file to write the barcodes skipped as not in --cell-barcodes with their number of reads (gzip-compressed if ending with .gz)"))
+            .arg(Arg::new("count_umis")
+                .long("count-umis")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with("dedup_by_position")
+                .help("Count distinct UMIs (molecules) per junction and barcode, skipping reads without a UMI instead of counting them by read name")))
+        .subcommand(Command::new("merge")
+            .about("Merge single-mode matrices of multiple samples")
+            .arg(Arg::new("input_dirs")
+                .required(true)
+                .num_args(1..)
+                .help("Output directories of single mode to merge"))
+            .arg(Arg::new("output_dir")
+                .short('o')
+                .long("output-dir")
+                .required(true)
+                .help("Output directory for the merged files"))
+            .arg(Arg::new("compression_level")
+                .long("compression-level")
+                .default_value("6")
+                .value_parser(clap::value_parser!(u32).range(0..=9))
+                .help("Gzip compression level for output files (0-9)"))
+            .arg(Arg::new("no_compress")
+                .long("no-compress")
+                .action(clap::ArgAction::SetTrue)
+                .help("Write uncompressed output files without the .gz suffix")))
+        .arg(Arg::new("log_file")
+            .long("log-file")
+            .global(true)
+            .display_order(1000) // Listed after the options of subcommands
             .value_parser(clap::value_parser!(String))
-            .help("Optional file specifying cell barcodes of interest"))
+            .help("Write logs to this file instead of stderr"))
         .arg(Arg::new("verbose")
             .short('v')
             .long("verbose")
+            .global(true)
+            .display_order(1000)
             .action(clap::ArgAction::SetTrue)
             .help("Enable verbose output to print all arguments"))
+        .arg(Arg::new("quiet")
+            .long("quiet")
+            .global(true)
+            .display_order(1000)
+            .action(clap::ArgAction::SetTrue)
+            .conflicts_with("verbose")
+            .help("Only print warnings and errors"))
         .get_matches();
 
+    // Initialize the logger with the appropriate level, writing to a log file if given
+    let verbose = matches.get_flag("verbose");
+    let mut logger = env_logger::Builder::from_default_env();
+    if verbose {
+        logger.filter(None, LevelFilter::Debug);
+    } else if matches.get_flag("quiet") {
+        logger.filter(None, LevelFilter::Warn);
+    } else {
+        logger.filter(None, LevelFilter::Info);
+    }
+    if let Some(path) = matches.get_one::<String>("log_file") {
+        let log_file = std::fs::File::create(path)?;
+        logger.target(env_logger::Target::Pipe(Box::new(log_file)));
+    }
+    logger.init();
+
+    // Merge matrices of multiple samples, or count junction reads in bulk or single mode
+    let (mode, matches) = match matches.subcommand() {
+        Some(("merge", merge_matches)) => {
+            let input_dirs: Vec<String> = merge_matches.get_many::<String>("input_dirs").unwrap().cloned().collect();
+            let output_dir = merge_matches.get_one::<String>("output_dir").unwrap();
+            let compression = if merge_matches.get_flag("no_compress") {
+                None
+            } else {
+                Some(Compression::new(*merge_matches.get_one::<u32>("compression_level").unwrap()))
+            };
+            info!("Running tosa merge");
+            info!("Output directory: {}", output_dir);
+            writer::prepare_output_dir(output_dir)?;
+            merge::merge_matrices(&input_dirs, output_dir, compression)?;
+            info!("Finished processing");
+            return Ok(());
+        }
+        Some((mode, count_matches)) => (mode, count_matches),
+        None => unreachable!("a subcommand is required"),
+    };
+
     // Parse arguments
-    let mode = matches.get_one::<String>("mode").unwrap();
-    let bam_file = matches.get_one::<String>("bam_file").unwrap();
+    let bam_files: Vec<&String> = matches.get_many::<String>("bam_file").unwrap().collect();
     let output_dir = matches.get_one::<String>("output_dir").unwrap();
-    let cell_barcode_file = matches.get_one::<String>("cell_barcode_file");
-    let min_anchor_length = *matches.get_one::<i64>("anchor_length").unwrap();
+    let cell_barcode_files: Vec<String> = matches
+        .try_get_many::<String>("cell_barcode_file")
+        .ok()
+        .flatten()
+        .map_or_else(Vec::new, |files| files.cloned().collect());
+    let reference = matches.get_one::<String>("reference");
+    let gtf_file = matches.get_one::<String>("gtf");
+    let gene_level = matches.get_flag("gene_level");
+    let drop_ambiguous_genes = matches.get_flag("drop_ambiguous_genes");
+    // Presets replace the defaults of options that are not given explicitly
+    let preset = matches.get_one::<String>("preset");
+    let long_read = preset.is_some_and(|preset| preset == "long-read");
+    let min_anchor_length = if long_read && is_default(matches, "anchor_length") {
+        20
+    } else {
+        *matches.get_one::<i64>("anchor_length").unwrap()
+    };
+    let min_left_anchor_length = matches.get_one::<i64>("min_left_anchor").copied();
+    let min_right_anchor_length = matches.get_one::<i64>("min_right_anchor").copied();
     let min_intron_length = *matches.get_one::<i64>("min_intron_length").unwrap();
-    let max_intron_length = *matches.get_one::<i64>("max_intron_length").unwrap();
+    let max_intron_length = if long_read && is_default(matches, "max_intron_length") {
+        1000000
+    } else {
+        *matches.get_one::<i64>("max_intron_length").unwrap()
+    };
     let max_loci = *matches.get_one::<u32>("max_loci").unwrap();
-    let verbose = matches.get_flag("verbose");
-
-    // Initialize the logger with the appropriate level
-    if verbose {
-        env_logger::Builder::from_default_env()
-            .filter(None, LevelFilter::Debug)
-            .init();
+    let fractional_multimappers = matches.get_flag("fractional_multimappers");
+    let require_nh = matches.get_flag("require_nh");
+    let min_aligned_length = *matches.get_one::<i64>("min_aligned_length").unwrap();
+    let min_insert_size = matches.get_one::<i64>("min_insert_size").copied();
+    let max_insert_size = matches.get_one::<i64>("max_insert_size").copied();
+    let min_boundary_qual = *matches.get_one::<u8>("min_boundary_qual").unwrap();
+    let library_type = match matches.get_one::<String>("library_type").unwrap().as_str() {
+        "fr-firststrand" => LibraryType::FrFirstStrand,
+        "fr-secondstrand" => LibraryType::FrSecondStrand,
+        _ => LibraryType::Unstranded,
+    };
+    let strand_source_names: Vec<String> = if long_read && is_default(matches, "strand_source") {
+        vec!["ts".to_string()]
     } else {
-        env_logger::Builder::from_default_env()
-            .filter(None, LevelFilter::Info)
-            .init();
+        matches.get_many::<String>("strand_source").unwrap().cloned().collect()
+    };
+    let strand_sources: Vec<StrandSource> = strand_source_names
+        .iter()
+        .map(|source| match source.as_str() {
+            "xs" => StrandSource::Xs,
+            "ts" => StrandSource::Ts,
+            "motif" => StrandSource::Motif,
+            _ => StrandSource::Library,
+        })
+        .collect();
+    let motif_strand = strand_sources.contains(&StrandSource::Motif);
+    let min_count = *matches.get_one::<u32>("min_count").unwrap();
+    let max_count = matches.get_one::<u32>("max_count").copied();
+    let collapse_radius = matches.get_one::<i64>("collapse_radius").copied();
+    let min_cells = get_optional::<u32>(matches, "min_cells").copied();
+    let min_barcode_count = get_optional::<u32>(matches, "min_barcode_count").copied();
+    let intron_retention = get_optional_flag(matches, "intron_retention");
+    let cpm = get_optional_flag(matches, "cpm");
+    let split_output = get_optional_flag(matches, "split_output");
+    let splice_site_seq = get_optional_flag(matches, "splice_site_seq");
+    let splice_motif = get_optional_flag(matches, "splice_motif");
+    let splice_site_flank = get_optional::<i64>(matches, "splice_site_flank").copied().unwrap_or(0);
+    let cell_barcode_tags: Vec<String> = match matches.try_get_many::<String>("cell_barcode_tags") {
+        Ok(Some(tags)) => tags.cloned().collect(),
+        _ => vec!["CB".to_string()],
+    };
+    let strip_barcode_suffix = get_optional_flag(matches, "strip_barcode_suffix");
+    let umi_tag = get_optional::<String>(matches, "umi_tag").map_or("UB", |tag| tag.as_str());
+    let count_umis = get_optional_flag(matches, "count_umis");
+    let barcode_from_name_pattern = get_optional::<String>(matches, "barcode_from_name");
+    let barcode_from_name = match barcode_from_name_pattern {
+        Some(pattern) => Some(tags::NameBarcode::parse(pattern)?),
+        None => None,
+    };
+    let dropped_barcodes = get_optional::<String>(matches, "dropped_barcodes");
+    let no_duplicates = matches.get_flag("no_duplicates");
+    let dedup_by_position = matches.get_flag("dedup_by_position");
+    let proper_pairs_only = matches.get_flag("proper_pairs_only");
+    let subsample = matches.get_one::<f64>("subsample").copied();
+    let seed = *matches.get_one::<u64>("seed").unwrap();
+    let chr_prefix = if matches.get_flag("add_chr_prefix") {
+        ChrPrefix::Add
+    } else if matches.get_flag("strip_chr_prefix") {
+        ChrPrefix::Strip
+    } else {
+        ChrPrefix::Keep
+    };
+    let coord_base = match matches.get_one::<String>("coord_base").map(|base| base.as_str()) {
+        Some("0") => CoordBase::Zero,
+        Some("1") => CoordBase::One,
+        _ => CoordBase::Exon,
+    };
+    let include_chroms = matches.get_one::<String>("include_chroms");
+    let exclude_chroms = matches.get_one::<String>("exclude_chroms");
+    let mito_name = matches.get_one::<String>("mito_name").unwrap();
+    let split_by_rg = get_optional_flag(matches, "split_by_rg");
+    let two_pass = matches.get_flag("two_pass");
+    let strict = matches.get_flag("strict");
+    let distinct_reads = matches.get_flag("distinct_reads");
+    let keep_unsupported = matches.get_flag("keep_unsupported");
+    let max_microexon_length = matches.get_one::<i64>("microexons").copied();
+    let spanning_bam = matches.get_one::<String>("spanning_bam");
+    let read_map = matches.get_one::<String>("read_map");
+    let bedgraph = matches.get_one::<String>("bedgraph");
+    let stats_json = matches.get_one::<String>("stats_json");
+    let dump_config = matches.get_one::<String>("dump_config");
+    let compression_level = *matches.get_one::<u32>("compression_level").unwrap();
+    let no_compress = matches.get_flag("no_compress");
+    let threads = *matches.get_one::<usize>("threads").unwrap();
+    let show_progress_bar = matches.get_flag("progress") || std::io::stderr().is_terminal();
+    let progress_interval = *matches.get_one::<u64>("progress_interval").unwrap();
+
+    let compression = if no_compress { None } else { Some(Compression::new(compression_level)) };
+
+    // Multiple BAM files are counted into one junction-by-sample matrix
+    if bam_files.len() > 1
+        && (cpm
+            || split_by_rg
+            || intron_retention
+            || splice_site_seq
+            || splice_motif
+            || split_output
+            || gene_level
+            || gtf_file.is_some()
+            || distinct_reads
+            || spanning_bam.is_some()
+            || read_map.is_some()
+            || bedgraph.is_some()
+            || dropped_barcodes.is_some()
+            || max_microexon_length.is_some()
+            || fractional_multimappers
+            || keep_unsupported
+            || stats_json.is_some())
+    {
+        return Err("--cpm, --split-by-rg, --intron-retention, --splice-site-seq, --splice-motif, --split-output, --gene-level, --gtf, --distinct-reads, --spanning-bam, --read-map, --bedgraph, --dropped-barcodes, --microexons, --fractional-multimappers, --keep-unsupported and --stats-json are not supported with multiple BAM files".into());
+    }
+
+    // Read groups are written as a matrix of multiple files
+    if split_by_rg && output_dir == "-" {
+        return Err("--split-by-rg is only supported with an output directory".into());
+    }
+    if split_output && output_dir == "-" {
+        return Err("--split-output is only supported with an output directory".into());
+    }
+    if keep_unsupported && output_dir == "-" {
+        return Err("--keep-unsupported is only supported with an output directory".into());
+    }
+    // The junction-by-sample matrix has only counts, while stranded junction keys are still used
+    if bam_files.len() > 1 && library_type != LibraryType::Unstranded && reference.is_some() {
+        warn!("The Antisense column is not written with multiple BAM files");
+    }
+    if max_microexon_length.is_some() && output_dir == "-" {
+        return Err("--microexons is only supported with an output directory".into());
+    }
+
+    // The splice motif is only known for junctions after counting, so it can only be the last source
+    if motif_strand && strand_sources.last() != Some(&StrandSource::Motif) {
+        return Err("'motif' must be the last source of --strand-source".into());
+    }
+    if motif_strand && reference.is_none() {
+        return Err("--strand-source motif requires the --reference FASTA".into());
+    }
+
+    if max_count.is_some_and(|max_count| max_count < min_count) {
+        return Err("--max-count must not be less than --min-count".into());
+    }
+    if let (Some(min), Some(max)) = (min_insert_size, max_insert_size) {
+        if max < min {
+            return Err("--max-insert-size must not be less than --min-insert-size".into());
+        }
+    }
+
+    if let Some(fraction) = subsample {
+        if !(fraction > 0.0 && fraction <= 1.0) {
+            return Err("--subsample must be a fraction in (0, 1]".into());
+        }
+    }
+
+    // Writing to stdout is only possible for the single output file of bulk mode
+    if output_dir == "-" && mode != "bulk" {
+        return Err("Output to stdout ('-') is only supported in bulk mode".into());
+    }
+
+    // Gene counts are written next to the junction counts
+    if gene_level && output_dir == "-" {
+        return Err("--gene-level is only supported with an output directory".into());
+    }
+
+    // A stream from stdin can only be read once and without an index
+    if bam_files.iter().any(|bam_file| data_loader::is_stdin(bam_file)) {
+        if bam_files.len() > 1 || intron_retention || split_by_rg || two_pass {
+            return Err("Input from stdin ('-') is not supported with multiple BAM files, --intron-retention, --split-by-rg or --two-pass".into());
+        }
+        if threads > 1 {
+            warn!("Input from stdin ('-') is processed with a single thread");
+        }
     }
 
+    writer::prepare_output_dir(output_dir)?;
+
     // Log all arguments if verbose is enabled
     info!("Running tosa");
     info!("Mode: {}", mode);
-    info!("BAM file: {}", bam_file);
+    info!("BAM file: {}", bam_files.iter().join(", "));
+    if let Some(fasta) = reference {
+        info!("Reference: {}", fasta);
+    }
+    if let Some(gtf) = gtf_file {
+        info!("GTF file: {}", gtf);
+    }
+    if chr_prefix != ChrPrefix::Keep {
+        info!("Chromosome prefix: {:?}", chr_prefix);
+    }
+    if coord_base != CoordBase::Exon {
+        info!("Coordinate convention: {:?}", coord_base);
+    }
     info!("Output prefix: {}", output_dir);
+    if let Some(preset) = preset {
+        info!("Preset: {}", preset);
+    }
     info!("Minimum anchor length: {}", min_anchor_length);
+    if let Some(length) = min_left_anchor_length {
+        info!("Minimum left anchor length: {}", length);
+    }
+    if let Some(length) = min_right_anchor_length {
+        info!("Minimum right anchor length: {}", length);
+    }
     info!("Minimum intron length: {}",min_intron_length);
     info!("Maximum intron length: {}", max_intron_length);
     info!("Maximum loci (NH): {}", max_loci);
+    if fractional_multimappers {
+        info!("Fractional multimappers: {}", fractional_multimappers);
+    }
+    if require_nh {
+        info!("Require NH tag: {}", require_nh);
+    }
+    if min_aligned_length > 0 {
+        info!("Minimum aligned length: {}", min_aligned_length);
+    }
+    if min_boundary_qual > 0 {
+        info!("Minimum base quality at junction boundaries: {}", min_boundary_qual);
+    }
+    if let Some(min) = min_insert_size {
+        info!("Minimum insert size: {}", min);
+    }
+    if let Some(max) = max_insert_size {
+        info!("Maximum insert size: {}", max);
+    }
+    info!("Library type: {}", matches.get_one::<String>("library_type").unwrap());
+    info!("Strand source: {}", strand_source_names.join(","));
+    info!("Minimum count: {}", min_count);
+    if let Some(max_count) = max_count {
+        info!("Maximum count: {}", max_count);
+    }
+    if let Some(radius) = collapse_radius {
+        info!("Collapse radius: {}", radius);
+    }
+    info!("Skip duplicates: {}", no_duplicates);
+    if dedup_by_position {
+        info!("Deduplicate by alignment start: {}", dedup_by_position);
+    }
+    info!("Proper pairs only: {}", proper_pairs_only);
+    if split_by_rg {
+        info!("Split by read group: {}", split_by_rg);
+    }
+    if let Some(fraction) = subsample {
+        info!("Subsample fraction: {} (seed: {})", fraction, seed);
+    }
+    if no_compress {
+        info!("Compression: none");
+    } else {
+        info!("Compression level: {}", compression_level);
+    }
+    info!("Threads: {}", threads);
+    if two_pass {
+        info!("Two-pass mode: {}", two_pass);
+    }
+    if let Some(path) = spanning_bam {
+        info!("Spanning reads BAM: {}", path);
+    }
+    if let Some(path) = read_map {
+        info!("Read map: {}", path);
+    }
+    if let Some(path) = bedgraph {
+        info!("BedGraph: {}", path);
+    }
+    if let Some(length) = max_microexon_length {
+        info!("Maximum microexon length: {}", length);
+    }
+
+    // Write the effective configuration of the run
+    if let Some(path) = dump_config {
+        let config = stats::RunConfig {
+            version: VERSION.to_string(),
+            mode: mode.to_string(),
+            bam_files: bam_files.iter().map(|bam_file| bam_file.to_string()).collect(),
+            output_dir: output_dir.to_string(),
+            reference: reference.cloned(),
+            gtf: gtf_file.cloned(),
+            preset: preset.cloned(),
+            gene_level,
+            drop_ambiguous_genes,
+            anchor_length: min_anchor_length,
+            min_left_anchor: min_left_anchor_length,
+            min_right_anchor: min_right_anchor_length,
+            min_intron_length,
+            max_intron_length,
+            max_loci,
+            fractional_multimappers,
+            require_nh,
+            min_aligned_length,
+            min_insert_size,
+            max_insert_size,
+            min_boundary_qual,
+            library_type: matches.get_one::<String>("library_type").unwrap().to_string(),
+            strand_source: strand_source_names.clone(),
+            min_count,
+            max_count,
+            min_cells,
+            min_barcode_count,
+            collapse_radius,
+            intron_retention,
+            split_output,
+            splice_site_seq,
+            splice_motif,
+            splice_site_flank,
+            cpm,
+            include_chroms: include_chroms.cloned(),
+            exclude_chroms: exclude_chroms.cloned(),
+            mito_name: mito_name.to_string(),
+            chr_prefix: format!("{:?}", chr_prefix).to_lowercase(),
+            coord_base: format!("{:?}", coord_base).to_lowercase(),
+            cell_barcodes: cell_barcode_files.clone(),
+            cell_barcode_tags: cell_barcode_tags.clone(),
+            barcode_from_name: barcode_from_name_pattern.cloned(),
+            strip_barcode_suffix,
+            umi_tag: umi_tag.to_string(),
+            count_umis,
+            no_duplicates,
+            dedup_by_position,
+            proper_pairs_only,
+            subsample,
+            seed,
+            split_by_rg,
+            two_pass,
+            strict,
+            distinct_reads,
+            keep_unsupported,
+            microexons: max_microexon_length,
+            spanning_bam: spanning_bam.cloned(),
+            read_map: read_map.cloned(),
+            bedgraph: bedgraph.cloned(),
+            dropped_barcodes: dropped_barcodes.cloned(),
+            compression_level: compression.map(|level| level.level()),
+            threads,
+            progress_interval,
+        };
+        debug!("Writing {}", path);
+        stats::write_json(path, &config)?;
+    }
+
     // Load cell barcodes of interest
     let cell_barcodes_of_interest = if mode == "single" {
-        let barcodes = data_loader::load_cell_barcodes(cell_barcode_file)?;
+        info!("Cell barcode tags: {}", cell_barcode_tags.join(","));
+        if let Some(pattern) = barcode_from_name_pattern {
+            info!("Barcode from read name: {}", pattern);
+        }
+        if strip_barcode_suffix {
+            info!("Strip barcode suffix: {}", strip_barcode_suffix);
+        }
+        if let Some(cells) = min_cells {
+            info!("Minimum cells: {}", cells);
+        }
+        if let Some(count) = min_barcode_count {
+            info!("Minimum barcode count: {}", count);
+        }
+        info!("UMI tag: {}", umi_tag);
+        if count_umis {
+            info!("Count UMIs: {}", count_umis);
+        }
+        if let Some(path) = dropped_barcodes {
+            info!("Dropped barcodes: {}", path);
+        }
+        let barcodes = data_loader::load_cell_barcodes(&cell_barcode_files)?;
         info!(
             "Cell barcodes of interest: {}",
             if barcodes.is_empty() {
@@ -113,262 +593,706 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         HashSet::new()
     };
 
-    // Count total mapped reads in the BAM file
-    let mut bam_index_reader = IndexedReader::from_path(bam_file)?;
-    let stats = bam_index_reader.index_stats()?;
-    debug!("stats: {:?}", stats);
-    // Sum the mapped reads from all targets
-    let total_mapped_reads: u64 = stats.iter().map(|(_, _, mapped, _)| mapped).sum();
-    info!("Total number of reads: {}", total_mapped_reads);
-
-    // Open the BAM file again for processing
-    let mut bam_reader = bam::Reader::from_path(bam_file)?;
-
-    // Get reference names (chromosome names)
-    let header = bam_reader.header().to_owned();
-    let reference_names: Vec<String> = header
-        .target_names()
-        .iter()
-        .map(|name| String::from_utf8_lossy(name).to_string())
-        .collect();
-
-    // HashMaps to store counts by junction and optionally by cell barcode
-    let mut junction_counts: HashMap<String, HashMap<String, u32>> = HashMap::new();
-    let mut junction_totals: HashMap<String, u32> = HashMap::new();
-    let mut cell_barcodes: HashSet<String> = HashSet::new();
-
-    // Counter for tracking the number of reads processed
-    let mut read_count = 0;
-    let mut last_percentage = 0;
+    // Load chromosomes to include or exclude
+    let include_chroms = match include_chroms {
+        Some(value) => {
+            let chroms = data_loader::load_chrom_list(value)?;
+            info!("Included chromosomes: {}", chroms.iter().sorted().join(", "));
+            Some(chroms)
+        }
+        None => None,
+    };
+    let exclude_chroms = match exclude_chroms {
+        Some(value) => {
+            let chroms = data_loader::load_chrom_list(value)?;
+            info!("Excluded chromosomes: {}", chroms.iter().sorted().join(", "));
+            Some(chroms)
+        }
+        None => None,
+    };
+    let mito_names = data_loader::load_chrom_list(mito_name)?;
 
-    // HashSet to store supported junctions and HashMap to store buffered reads
-    let mut supported_junctions: HashSet<String> = HashSet::new();
-    let mut buffered_reads: HashMap<String, Vec<(Option<String>, i64)>> = HashMap::new();
+    let params = counter::CountParams {
+        mode,
+        extractor: JunctionExtractor::builder()
+            .min_anchor_length(min_anchor_length)
+            .min_left_anchor_length(min_left_anchor_length)
+            .min_right_anchor_length(min_right_anchor_length)
+            .min_intron_length(min_intron_length)
+            .max_intron_length(max_intron_length)
+            .max_loci(max_loci)
+            .require_nh(require_nh)
+            .min_aligned_length(min_aligned_length)
+            .min_insert_size(min_insert_size)
+            .max_insert_size(max_insert_size)
+            .min_boundary_qual(min_boundary_qual)
+            .library_type(library_type)
+            .strand_sources(strand_sources)
+            .build(),
+        no_duplicates,
+        dedup_by_position,
+        proper_pairs_only,
+        subsample,
+        seed,
+        cell_barcode_tags: &cell_barcode_tags,
+        barcode_from_name: barcode_from_name.as_ref(),
+        strip_barcode_suffix,
+        umi_tag,
+        count_umis,
+        chr_prefix,
+        coord_base,
+        include_chroms: include_chroms.as_ref(),
+        mito_names: &mito_names,
+        exclude_chroms: exclude_chroms.as_ref(),
+        split_by_rg,
+        cell_barcodes_of_interest: &cell_barcodes_of_interest,
+        show_progress_bar,
+        progress_interval,
+        two_pass,
+        strict,
+        track_distinct_reads: distinct_reads,
+        track_dropped_barcodes: dropped_barcodes.is_some(),
+        max_microexon_length,
+        fractional_multimappers,
+    };
 
-    // HashMap to store processed reads by junction
-    let mut processed_reads: HashMap<String, HashSet<String>> = HashMap::new();
+    // Load annotated introns and genes from the GTF file
+    let (annotated_introns, gene_index) = match gtf_file {
+        Some(gtf) => {
+            let introns = data_loader::load_annotated_introns(gtf)?;
+            let genes = data_loader::load_genes(gtf)?;
+            info!("Annotated introns: {}", introns.len());
+            info!("Annotated genes: {}", genes.len());
+            (Some(introns), Some(genes))
+        }
+        None => (None, None),
+    };
 
-    // Iterate over each read in the BAM file
-    for result in bam_reader.records() {
-        let record = result?;
-        read_count += 1;
+    // Open the reference FASTA before counting; splice sites are fetched only for the junctions that are written
+    // Reads of stranded libraries are compared with the splice motif for the Antisense column
+    let stranded = library_type != LibraryType::Unstranded;
+    if stranded && reference.is_none() && mode == "bulk" {
+        info!("The Antisense column is not written without --reference for the splice motif");
+    }
+    let splice_site_reader = match reference.filter(|_| splice_site_seq || splice_motif || motif_strand || stranded) {
+        Some(fasta) => Some(sequence::SpliceSiteReader::from_path(fasta, chr_prefix, splice_site_flank)?),
+        None => None,
+    };
 
-        // Calculate and log progress at each 1% increment
-        let progress_percentage = (read_count * 100) / total_mapped_reads;
-        if progress_percentage > last_percentage {
-            info!("Progress: {}% ({} / {})", progress_percentage, read_count, total_mapped_reads);
-            last_percentage = progress_percentage;
-        }
+    // Junctions left without a strand by the reads are stranded by their splice motif after counting
+    // (the read map keeps the junctions as counted)
+    let assign_motif_strands = |counts: counter::JunctionCounts| match splice_site_reader.as_ref().filter(|_| motif_strand) {
+        Some(reader) => counts.map_keys(|junction| reader.assign_strand(junction)),
+        None => counts,
+    };
 
-        // Skip read if NH tag exceeds max_loci
-        if let Ok(Aux::U8(nh)) = record.aux(b"NH") {
-            if nh > max_loci as u8 {
-                // debug!("Skipping read {} with NH > max_loci ({})", std::str::from_utf8(record.qname()).unwrap(), nh);
-                continue; // Skip this read
+    // Count junction reads in each BAM file
+    let counts = if bam_files.len() > 1 {
+        // Count junctions by sample, with samples named after the BAM files
+        let mut sample_names: Vec<String> = Vec::new();
+        let mut sample_counts: HashMap<String, HashMap<String, u32>> = HashMap::new();
+        let mut reference_names: Vec<String> = Vec::new();
+        for bam_file in &bam_files {
+            let sample_name = data_loader::sample_name(bam_file);
+            if sample_names.contains(&sample_name) {
+                return Err(format!("Duplicate sample name '{}'; BAM files must have distinct names", sample_name).into());
             }
-        } else if let Ok(Aux::I32(nh)) = record.aux(b"NH") {
-            if nh > max_loci as i32 {
-                // debug!("Skipping read {} with NH > max_loci ({})", std::str::from_utf8(record.qname()).unwrap(), nh);
-                continue; // Skip this read
+            info!("Processing sample {} ({})", sample_name, bam_file);
+            let counts = assign_motif_strands(counter::count_bam(bam_file, reference, &params, threads, None, None, compression)?);
+            for (junction, count) in counts.junction_totals {
+                sample_counts.entry(junction).or_default().insert(sample_name.clone(), count);
             }
-        }
-
-        // Extract reference name (chromosome) and start position
-        let ref_name = reference_names[record.tid() as usize].clone();
-        let mut current_pos = record.pos(); // Start of the alignment
-
-        // Extract Cell Barcode (CB) from tags if in single mode
-        let cell_barcode = if mode == "single" {
-            match record.aux(b"CB") {
-                Ok(Aux::String(cb_str)) => Some(cb_str.to_string()),
-                _ => None,
+            // Chromosomes are sorted in the header order of the first BAM file, followed by those only in later files
+            for name in counts.reference_names {
+                if !reference_names.contains(&name) {
+                    reference_names.push(name);
+                }
             }
-        } else {
-            None
-        };
+            sample_names.push(sample_name);
+        }
 
-        // Skip read if its barcode is not in the list of interest
-        if let Some(cb) = &cell_barcode {
-            if cell_barcode_file.is_some() && !cell_barcodes_of_interest.is_empty() && !cell_barcodes_of_interest.contains(cb) {
-            continue;
-            }
+        // Merge nearby junctions, then drop low-count junctions and write the junction-by-sample matrix
+        if let Some(radius) = collapse_radius {
+            let merged = junction::collapse_junctions(
+                &mut HashMap::new(),
+                &mut sample_counts,
+                &mut [],
+                &mut HashMap::new(),
+                &mut HashMap::new(),
+                radius,
+            );
+            info!("Junctions merged by collapse radius: {}", merged);
+        }
+        let removed = filter::filter_features_by_min_count(&mut sample_counts, min_count);
+        info!("Junctions removed by minimum count: {}", removed);
+        if let Some(max_count) = max_count {
+            let removed = filter::filter_features_by_max_count(&mut sample_counts, max_count);
+            info!("Junctions removed by maximum count: {}", removed);
         }
+        info!("Writing output files");
+        let sample_counts = coord_base.rekey(sample_counts);
+        writer::write_sample_matrix(output_dir, &sample_counts, &sample_names, &reference_names, compression)?;
+        info!("Finished processing");
+        return Ok(());
+    } else {
+        assign_motif_strands(counter::count_bam(bam_files[0], reference, &params, threads, spanning_bam, read_map, compression)?)
+    };
+    let mut junction_counts = counts.junction_counts;
+    let mut junction_totals = counts.junction_totals;
+    let mut multimapped_totals = counts.multimapped_totals;
+    let mut minus_read_totals = counts.minus_read_totals;
+    let mut read_totals = counts.read_totals;
+    let mut junction_positions = counts.junction_positions;
+    let mut max_overhangs = counts.max_overhangs;
+    let unsupported_totals = counts.unsupported_totals;
+    let microexon_totals = counts.microexon_totals;
+    let mut weighted_counts = counts.weighted_counts;
+    let weighted_totals = counts.weighted_totals;
+    let mut cell_barcodes = counts.cell_barcodes;
+    let reference_names = counts.reference_names;
+    let counted_reads = counts.counted_reads;
+    info!("Number of reads counted into junctions: {}", counted_reads);
+    // Reads skipped by the filters, in the order they are applied
+    info!("Reads skipped by the NH filter: {}", counts.skipped_nh);
+    if mode == "single" {
+        info!("Reads skipped without a barcode of interest: {}", counts.missing_barcode + counts.skipped_barcode);
+    }
+    info!("Reads skipped with introns out of the length range: {}", counts.skipped_intron_length);
+    info!("Reads skipped with junctions lacking anchors: {}", counts.skipped_anchor);
+    let spliced_fraction = if counts.processed_reads > 0 { counted_reads as f64 / counts.processed_reads as f64 } else { 0.0 };
+    info!("Fraction of processed reads counted into junctions: {:.4}", spliced_fraction);
+    info!("Number of reads counted into mitochondrial junctions: {}", counts.mito_reads);
+    if distinct_reads {
+        info!("Number of distinct reads counted into junctions: {}", counts.distinct_reads.len());
+    }
+    if let Some(path) = dropped_barcodes {
+        info!("Barcodes skipped as not of interest: {}", counts.dropped_barcodes.len());
+        writer::write_dropped_barcodes(path, &counts.dropped_barcodes, compression)?;
+    }
 
-        // If a cell barcode is present (for single mode), or always process for bulk mode
-        if mode == "bulk" || cell_barcode.is_some() {
-            if let Some(cb_str) = &cell_barcode {
-                cell_barcodes.insert(cb_str.clone());
-            }
+    // Run statistics before junctions are filtered
+    let junction_keys: Vec<&String> = if mode == "single" { junction_counts.keys().collect() } else { junction_totals.keys().collect() };
+    let mito_junctions = junction_keys
+        .iter()
+        .filter(|junction| junction::parse_junction(junction).is_some_and(|(chrom, _, _)| mito_names.contains(chrom)))
+        .count();
+    let run_stats = stats::RunStats {
+        total_reads: counts.total_mapped_reads,
+        processed_reads: counts.processed_reads,
+        skipped_nh: counts.skipped_nh,
+        skipped_anchor: counts.skipped_anchor,
+        skipped_intron_length: counts.skipped_intron_length,
+        skipped_barcode: if mode == "single" { Some(counts.missing_barcode + counts.skipped_barcode) } else { None },
+        junctions: if mode == "single" { junction_counts.len() } else { junction_totals.len() },
+        barcodes: if mode == "single" { Some(cell_barcodes.len()) } else { None },
+        counted_reads,
+        spliced_fraction,
+        mito_reads: counts.mito_reads,
+        mito_junctions,
+        distinct_reads: if distinct_reads { Some(counts.distinct_reads.len()) } else { None },
+    };
 
-            let cigar_vec = record.cigar(); // Create a longer-lived binding for the cigar data
-            let cigars: Vec<_> = cigar_vec.iter().collect();
-            for i in 0..cigars.len() {
-                if let Cigar::RefSkip(len) = cigars[i] {
-                    // Check intron length constraints
-                    let intron_length = *len as i64;
-                    if intron_length < min_intron_length || intron_length > max_intron_length {
-                        // Skip junctions outside the specified intron length range
-                        current_pos += intron_length;
-                        continue;
-                    }
+    // Merge junctions offset by alignment noise onto their highest-count neighbor
+    if let Some(radius) = collapse_radius {
+        let merged = junction::collapse_junctions(
+            &mut junction_totals,
+            &mut junction_counts,
+            &mut [&mut multimapped_totals, &mut minus_read_totals, &mut read_totals],
+            &mut junction_positions,
+            &mut max_overhangs,
+            radius,
+        );
+        info!("Junctions merged by collapse radius: {}", merged);
+    }
 
-                    // Calculate left anchor length by accumulating lengths before the RefSkip
-                    let mut left_anchor_length = 0;
-                    let mut j = i; // Start from the current CIGAR index
-                    while j > 0 {
-                        j -= 1; // Move to the previous CIGAR element
-                        match cigars[j] {
-                            Cigar::Match(l) | Cigar::Equal(l) | Cigar::Diff(l) => {
-                                left_anchor_length += *l as i64;
-                                if left_anchor_length >= min_anchor_length {
-                                    break; // Stop if the threshold is met
-                                }
-                            }
-                            Cigar::RefSkip(_) => continue, // Skip RefSkip and keep checking alignment elements
-                            _ => break, // Stop accumulating for other operations
-                        }
-                    }
-                    let has_left_anchor = left_anchor_length >= min_anchor_length;
-
-                    // Calculate right anchor length by accumulating lengths after the RefSkip
-                    let mut right_anchor_length = 0;
-                    let mut k = i + 1; // Start from the next CIGAR index
-                    while k < cigars.len() {
-                        match cigars[k] {
-                            Cigar::Match(r) | Cigar::Equal(r) | Cigar::Diff(r) => {
-                                right_anchor_length += *r as i64;
-                                if right_anchor_length >= min_anchor_length {
-                                    break; // Stop if the threshold is met
-                                }
-                            }
-                            Cigar::RefSkip(_) => { k += 1; continue; } // Skip RefSkip and keep checking alignment elements
-                            _ => break, // Stop accumulating for other operations
-                        }
-                        k += 1; // Move to the next CIGAR element
-                    }
-                    let has_right_anchor = right_anchor_length >= min_anchor_length;
-
-                    let start = current_pos;
-                    let end = start + intron_length + 1;
-                    let junction_coords = format!("{}:{}-{}", ref_name, start, end);
-
-                    if has_left_anchor && has_right_anchor {
-                        // Mark as supported and process buffered reads
-                        supported_junctions.insert(junction_coords.clone());
-                        if let Some(buffered) = buffered_reads.remove(&junction_coords) {
-                            for (buffered_cb, _buffered_pos) in buffered {
-                                junction::process_junction(
-                                    &junction_coords,
-                                    buffered_cb.as_ref(),
-                                    &mut junction_counts,
-                                    &mut junction_totals,
-                                    &mut processed_reads, // Pass the processed reads map
-                                    std::str::from_utf8(record.qname()).unwrap(), // Pass read name
-                                    &mode,
-                                );
-                            }
-                        }
-                    }
+    // Sum counts by splice site before junctions are filtered
+    let (donor_totals, acceptor_totals) = junction::count_splice_sites(&junction_totals);
 
-                    // Process or buffer the current read
-                    if supported_junctions.contains(&junction_coords) {
-                        junction::process_junction(
-                            &junction_coords,
-                            cell_barcode.as_ref(),
-                            &mut junction_counts,
-                            &mut junction_totals,
-                            &mut processed_reads, // Pass the processed reads map
-                            std::str::from_utf8(record.qname()).unwrap(), // Pass read name
-                            &mode,
-                        );
-                    } else {
-                        buffered_reads
-                            .entry(junction_coords.clone())
-                            .or_insert_with(Vec::new)
-                            .push((cell_barcode.clone(), current_pos));
-                    }
-                    current_pos += intron_length;
-                } else if let Cigar::SoftClip(_len) = cigars[i] {
-                    continue;
-                } else {
-                    current_pos += match cigars[i] {
-                        Cigar::Match(l) | Cigar::Ins(l) | Cigar::Del(l) => *l as i64,
-                        _ => 0,
-                    };
-                }
-            }
-        }
+    // Drop low-count barcodes (e.g., empty droplets) before writing
+    if let Some(min_barcode_count) = min_barcode_count {
+        let removed = filter::filter_barcodes_by_min_count(&mut junction_counts, &mut cell_barcodes, min_barcode_count);
+        info!("Barcodes removed by minimum barcode count: {}", removed);
     }
 
+    // Drop low-count junctions before writing
+    let removed = if mode == "single" {
+        filter::filter_features_by_min_count(&mut junction_counts, min_count)
+    } else {
+        filter::filter_totals_by_min_count(&mut junction_totals, min_count)
+    };
+    info!("Junctions removed by minimum count: {}", removed);
+    if let Some(max_count) = max_count {
+        let removed = if mode == "single" {
+            filter::filter_features_by_max_count(&mut junction_counts, max_count)
+        } else {
+            filter::filter_totals_by_max_count(&mut junction_totals, max_count)
+        };
+        info!("Junctions removed by maximum count: {}", removed);
+    }
+    if let Some(min_cells) = min_cells {
+        let removed = filter::filter_features_by_min_cells(&mut junction_counts, min_cells);
+        info!("Junctions removed by minimum cells: {}", removed);
+    }
+
+    // Count reads retaining the introns of the remaining junctions in a second pass
+    let retained_reads = if intron_retention {
+        info!("Counting intron retention reads");
+        let junctions: Vec<&String> = junction_totals.keys().collect();
+        Some(boundary::count_retained_reads(bam_files[0], reference, &params, &junctions)?)
+    } else {
+        None
+    };
+
     // Write results based on mode
     info!("Writing output files");
+    // Weighted counts of the remaining junctions and barcodes are written instead of read counts with --fractional-multimappers
+    weighted_counts.retain(|junction, weighted_cell_counts| match junction_counts.get(junction) {
+        Some(cell_counts) => {
+            weighted_cell_counts.retain(|barcode, _| cell_counts.contains_key(barcode));
+            true
+        }
+        None => false,
+    });
+    if let Some(path) = bedgraph {
+        if fractional_multimappers {
+            let totals: HashMap<String, f64> = if mode == "single" {
+                weighted_counts.iter().map(|(junction, cell_counts)| (junction.clone(), cell_counts.values().sum())).collect()
+            } else {
+                junction_totals.keys().map(|junction| (junction.clone(), weighted_totals[junction])).collect()
+            };
+            writer::write_bedgraph(path, &totals, &reference_names, compression)?;
+        } else {
+            let summed_totals: HashMap<String, u32>;
+            let totals = if mode == "single" {
+                summed_totals = junction_counts.iter().map(|(junction, cell_counts)| (junction.clone(), cell_counts.values().sum())).collect();
+                &summed_totals
+            } else {
+                &junction_totals
+            };
+            writer::write_bedgraph(path, totals, &reference_names, compression)?;
+        }
+    }
     if mode == "single" {
-        // Prepare output files with compression
-        let mut matrix_file = GzEncoder::new(File::create(format!("{}/matrix.mtx.gz", output_dir))?, Compression::default());
-        let mut barcodes_file = GzEncoder::new(File::create(format!("{}/barcodes.tsv.gz", output_dir))?, Compression::default());
-        let mut features_file = GzEncoder::new(File::create(format!("{}/features.tsv.gz", output_dir))?, Compression::default());
-        let mut output_tsv = GzEncoder::new(File::create(format!("{}/junction_barcodes.tsv.gz", output_dir))?, Compression::default());
-
-        // Write barcodes.tsv.gz
-        debug!("Writing barcodes.tsv.gz");
-        let barcode_list: Vec<_> = cell_barcodes.iter().sorted().collect();
-        for barcode in &barcode_list {
-            writeln!(barcodes_file, "{}", barcode)?;
-        }
-
-        // Write features.tsv.gz
-        debug!("Writing features.tsv.gz");
-        let feature_list: Vec<_> = junction_counts.keys().sorted().collect();
-        for feature in &feature_list {
-            writeln!(features_file, "{}", feature)?;
-        }
-
-        // Buffers to accumulate lines for matrix.mtx.gz and output.tsv.gz
-        let mut matrix_buffer: Vec<String> = Vec::new();
-        let mut tsv_buffer: Vec<String> = Vec::new();
-
-        // Add the header lines to the matrix buffer
-        matrix_buffer.push("%%MatrixMarket matrix coordinate integer general".to_string());
-        matrix_buffer.push("%".to_string());
-        matrix_buffer.push(format!(
-            "{} {} {}",
-            feature_list.len(),
-            barcode_list.len(),
-            junction_counts.values().map(|c| c.len()).sum::<usize>()
-        ));
-
-        // Add sparse matrix data and TSV data to the buffers
-        debug!("Writing matrix.mtx.gz and junction_barcodes.tsv.gz");
-        let barcode_map: HashMap<_, _> = barcode_list.iter().enumerate().map(|(i, b)| (b.as_str(), i)).collect();
-        tsv_buffer.push("Feature\tBarcode\tCount".to_string());
-        for (i, feature) in feature_list.iter().enumerate() {
-            if let Some(cell_counts) = junction_counts.get(*feature) {
-                for (barcode, count) in cell_counts {
-                    if let Some(&j) = barcode_map.get(barcode.as_str()) {
-                        matrix_buffer.push(format!("{} {} {}", i + 1, j + 1, count));
-                        tsv_buffer.push(format!("{}\t{}\t{}", feature, barcode, count));
+        // Write the gene-by-barcode matrix in a subdirectory with the same layout
+        if let Some(index) = gene_index.as_ref().filter(|_| gene_level) {
+            let (gene_counts, gene_names) = index.sum_counts_by_gene(&junction_counts, drop_ambiguous_genes);
+            info!("Genes with junction reads: {}", gene_counts.len());
+            let gene_dir = format!("{}/gene", output_dir);
+            writer::prepare_output_dir(&gene_dir)?;
+            writer::write_matrix(&gene_dir, &gene_counts, &cell_barcodes, Some(&gene_names), "Gene", compression)?;
+        }
+
+        // Junctions are named after their genes before their coordinates are converted for output
+        let junction_names = gene_index.as_ref().map(|index| coord_base.rekey(index.feature_names(junction_counts.keys())));
+        if fractional_multimappers {
+            let weighted_counts = coord_base.rekey(weighted_counts);
+            writer::write_matrix(output_dir, &weighted_counts, &cell_barcodes, junction_names.as_ref(), "Junction", compression)?;
+            writer::write_junction_totals(output_dir, &weighted_counts, compression)?;
+        } else {
+            let junction_counts = coord_base.rekey(junction_counts);
+            writer::write_matrix(output_dir, &junction_counts, &cell_barcodes, junction_names.as_ref(), "Junction", compression)?;
+            writer::write_junction_totals(output_dir, &junction_counts, compression)?;
+        }
+    } else if mode == "bulk" {
+        let mut header = vec!["Junction", "Strand", "IntronLength", "Count", "Unique", "Multimapped", "UniquePositions", "Entropy", "Reads", "MaxOverhang"];
+        // Reads antisense to the splice motif can only be told apart in stranded libraries
+        let antisense_reader = splice_site_reader.as_ref().filter(|_| stranded);
+        if antisense_reader.is_some() {
+            header.push("Antisense");
+        }
+        if cpm {
+            header.push("CPM");
+        }
+        if retained_reads.is_some() {
+            header.push("Retained");
+        }
+        if annotated_introns.is_some() {
+            header.push("Annotated");
+        }
+        if splice_site_seq {
+            header.push("DonorSeq");
+            header.push("AcceptorSeq");
+        }
+        if splice_motif {
+            header.push("Motif");
+        }
+        if gene_index.is_some() {
+            header.push("GeneID");
+            header.push("GeneName");
+        }
+        // Function to create a junction file with the header
+        let create_junction_file = |file_name: &str| -> Result<writer::OutputFile, Box<dyn std::error::Error>> {
+            debug!("Writing {}.gz", file_name);
+            let mut output_file = writer::create_output(output_dir, file_name, compression)?;
+            writeln!(output_file, "{}", header.join("\t"))?;
+            Ok(output_file)
+        };
+        // A split output is written to one file per chromosome, each created at the first junction of its chromosome
+        let mut output_file = if split_output { None } else { Some(create_junction_file("junction.tsv")?) };
+        let mut output_chrom = "";
+        // Junctions are sorted by position in the header order of chromosomes (e.g., for tabix)
+        for junction in junction::sort_by_position(junction_totals.keys(), &reference_names) {
+            if split_output {
+                let chrom = junction::parse_junction(junction).map_or("", |(chrom, _, _)| chrom);
+                if output_file.is_none() || chrom != output_chrom {
+                    if let Some(output_file) = output_file.take() {
+                        output_file.finish()?;
                     }
+                    output_file = Some(create_junction_file(&format!("junction.{}.tsv", chrom))?);
+                    output_chrom = chrom;
                 }
             }
+            let output_file = output_file.as_mut().unwrap();
+            let count = &junction_totals[junction];
+            // Reads weighted by 1/NH are written as the count with --fractional-multimappers
+            let weighted_count = if fractional_multimappers { weighted_totals[junction] } else { *count as f64 };
+            // Split the count into unique (NH = 1) and multimapped (NH > 1) reads
+            let multimapped = multimapped_totals.get(junction).copied().unwrap_or(0);
+            let (_, strand) = junction::split_strand(junction);
+            let intron_length = junction::parse_junction(junction).map_or(0, |(_, start, end)| end - start - 1);
+            let mut row = vec![
+                coord_base.apply(junction),
+                strand.unwrap_or(".").to_string(),
+                intron_length.to_string(),
+                if fractional_multimappers { weighted_count.to_string() } else { count.to_string() },
+                (count - multimapped).to_string(),
+                multimapped.to_string(),
+                junction_positions.get(junction).map_or(0, |positions| positions.len()).to_string(),
+                // Normalized entropy of the alignment starts, low for reads piled up at one position
+                format!("{:.4}", junction_positions.get(junction).map_or(0.0, junction::position_entropy)),
+                // Alignments supporting the junction, counting both mates of a fragment counted once in Count
+                read_totals.get(junction).copied().unwrap_or(0).to_string(),
+                // Longest anchor of supporting reads, as LeafCutter and STAR track the maximum overhang
+                max_overhangs.get(junction).copied().unwrap_or(0).to_string(),
+            ];
+            if let Some(reader) = antisense_reader {
+                // Reads whose strand differs from the splice motif, unknown for non-canonical motifs
+                let minus_reads = minus_read_totals.get(junction).copied().unwrap_or(0);
+                row.push(match reader.motif_strand(junction) {
+                    Some('+') => minus_reads.to_string(),
+                    Some(_) => count.saturating_sub(minus_reads).to_string(),
+                    None => ".".to_string(),
+                });
+            }
+            if cpm {
+                // Normalize by the number of reads counted into junctions
+                let cpm_value = if counted_reads > 0 { weighted_count / counted_reads as f64 * 1e6 } else { 0.0 };
+                row.push(format!("{:.4}", cpm_value));
+            }
+            if let Some(retained) = &retained_reads {
+                row.push(retained.get(junction).copied().unwrap_or(0).to_string());
+            }
+            if let Some(introns) = &annotated_introns {
+                let (coords, _strand) = junction::split_strand(junction);
+                row.push(if introns.contains(coords) { "1" } else { "0" }.to_string());
+            }
+            if let Some(reader) = splice_site_reader.as_ref().filter(|_| splice_site_seq) {
+                let (donor, acceptor) = reader.splice_sites(junction);
+                row.push(donor);
+                row.push(acceptor);
+            }
+            if let Some(reader) = splice_site_reader.as_ref().filter(|_| splice_motif) {
+                row.push(reader.motif(junction).to_string());
+            }
+            if let Some(index) = &gene_index {
+                let (gene_id, gene_name) = index.annotate(junction).unwrap_or((".".to_string(), ".".to_string()));
+                row.push(gene_id);
+                row.push(gene_name);
+            }
+            writeln!(output_file, "{}", row.join("\t"))?;
+        }
+        if let Some(output_file) = output_file {
+            output_file.finish()?;
         }
 
-        // Write the accumulated lines to the compressed output files
-        for line in matrix_buffer {
-            writeln!(matrix_file, "{}", line)?;
+        // Write the junction-by-read-group matrix with the read groups of the header as columns
+        if split_by_rg {
+            let read_groups = data_loader::load_read_groups(bam_files[0], reference)?;
+            info!("Read groups: {}", read_groups.len());
+            junction_counts.retain(|junction, _| junction_totals.contains_key(junction));
+            let junction_names = gene_index.as_ref().map(|index| coord_base.rekey(index.feature_names(junction_counts.keys())));
+            if fractional_multimappers {
+                weighted_counts.retain(|junction, _| junction_totals.contains_key(junction));
+                let weighted_counts = coord_base.rekey(weighted_counts);
+                writer::write_matrix(output_dir, &weighted_counts, &read_groups, junction_names.as_ref(), "Junction", compression)?;
+            } else {
+                let junction_counts = coord_base.rekey(junction_counts);
+                writer::write_matrix(output_dir, &junction_counts, &read_groups, junction_names.as_ref(), "Junction", compression)?;
+            }
         }
-        for line in tsv_buffer {
-            writeln!(output_tsv, "{}", line)?;
+
+        // Write read counts summed by gene
+        if let Some(index) = gene_index.as_ref().filter(|_| gene_level) {
+            let (gene_totals, gene_names) = index.sum_totals_by_gene(&junction_totals, drop_ambiguous_genes);
+            info!("Genes with junction reads: {}", gene_totals.len());
+            writer::write_gene_counts(output_dir, &gene_totals, &gene_names, compression)?;
         }
 
-    } else if mode == "bulk" {
-        let mut output_file = GzEncoder::new(File::create(format!("{}/junction.tsv.gz", output_dir))?, Compression::default());
-        debug!("Writing junction.tsv.gz");
-        writeln!(output_file, "Junction\tCount")?;
-        for (junction, count) in junction_totals.iter().sorted() {
-            writeln!(output_file, "{}\t{}", junction, count)?;
+        // Write donor and acceptor site counts (swapped between the left and right sites on the minus strand)
+        if output_dir != "-" {
+            writer::write_site_counts(output_dir, "donor.tsv", &donor_totals, compression)?;
+            writer::write_site_counts(output_dir, "acceptor.tsv", &acceptor_totals, compression)?;
         }
     }
 
+    // Write junctions failing the anchor test (e.g., to tune the anchor length)
+    if keep_unsupported {
+        info!("Junctions never supported by an anchored read: {}", unsupported_totals.len());
+        let unsupported_totals = coord_base.rekey(unsupported_totals);
+        writer::write_unsupported_junctions(output_dir, &unsupported_totals, &reference_names, compression)?;
+    }
+
+    // Write reads spanning microexons, which are otherwise counted for each of their two junctions
+    if max_microexon_length.is_some() {
+        info!("Microexons: {}", microexon_totals.len());
+        writer::write_microexons(output_dir, &microexon_totals, &reference_names, coord_base, compression)?;
+    }
+
+    // Write run statistics
+    if let Some(path) = stats_json {
+        debug!("Writing {}", path);
+        stats::write_json(path, &run_stats)?;
+    }
+
     info!("Finished processing");
     Ok(())
 }
+
+// Function to build the arguments shared by the bulk and single subcommands
+fn count_args() -> Vec<Arg> {
+    vec![
+            Arg::new("reference")
+                .short('r')
+                .long("reference")
+                .value_parser(clap::value_parser!(String))
+                .help("Reference FASTA file, required to decode CRAM input"),
+            Arg::new("preset")
+                .long("preset")
+                .value_parser(["long-read"])
+                .help("Preset of defaults for the data type; 'long-read' (e.g., ONT or PacBio aligned by minimap2) sets --anchor-length 20, --max-intron-length 1000000 and --strand-source ts unless they are given"),
+            Arg::new("anchor_length")
+                .short('a')
+                .long("anchor-length")
+                .default_value("8")
+                .value_parser(clap::value_parser!(i64))
+                .help("Minimum anchor length for both sides of junctions"),
+            Arg::new("min_left_anchor")
+                .long("min-left-anchor")
+                .value_parser(clap::value_parser!(i64))
+                .help("Minimum anchor length for the upstream (left) side of junctions, overriding --anchor-length"),
+            Arg::new("min_right_anchor")
+                .long("min-right-anchor")
+                .value_parser(clap::value_parser!(i64))
+                .help("Minimum anchor length for the downstream (right) side of junctions, overriding --anchor-length"),
+            Arg::new("min_intron_length")
+                .short('m')
+                .long("min-intron-length")
+                .default_value("70")
+                .value_parser(clap::value_parser!(i64))
+                .help("Minimum intron length for junctions"),
+            Arg::new("max_intron_length")
+                .short('M')
+                .long("max-intron-length")
+                .default_value("500000")
+                .value_parser(clap::value_parser!(i64))
+                .help("Maximum intron length for junctions"),
+            Arg::new("max_loci")
+                .short('l')
+                .long("max-loci")
+                .default_value("1")
+                .value_parser(clap::value_parser!(u32))
+                .help("Maximum number of loci the read maps to"),
+            Arg::new("min_aligned_length")
+                .long("min-aligned-length")
+                .default_value("0")
+                .value_parser(clap::value_parser!(i64))
+                .help("Skip reads whose aligned length (sum of M, = and X operations) is below this length"),
+            Arg::new("min_insert_size")
+                .long("min-insert-size")
+                .value_parser(clap::value_parser!(i64).range(0..))
+                .help("Skip properly paired reads whose absolute insert size (TLEN) is below this size"),
+            Arg::new("max_insert_size")
+                .long("max-insert-size")
+                .value_parser(clap::value_parser!(i64).range(0..))
+                .help("Skip properly paired reads whose absolute insert size (TLEN) is above this size, e.g., mis-paired mates with spurious long junctions"),
+            Arg::new("min_boundary_qual")
+                .long("min-boundary-qual")
+                .default_value("0")
+                .value_parser(clap::value_parser!(u8))
+                .help("Skip junctions whose flanking read bases (next to the N operation) have a base quality below this value"),
+            Arg::new("fractional_multimappers")
+                .long("fractional-multimappers")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with_all(["collapse_radius", "gene_level"])
+                .help("Count each read as 1/NH instead of 1, so that a read mapped to NH loci (up to --max-loci) contributes 1/NH to each of them; counts are written as real numbers, while --min-count, --max-count and --min-barcode-count still apply to read counts"),
+            Arg::new("require_nh")
+                .long("require-nh")
+                .action(clap::ArgAction::SetTrue)
+                .help("Skip reads lacking an NH tag instead of counting them regardless of --max-loci"),
+            Arg::new("library_type")
+                .long("library-type")
+                .default_value("unstranded")
+                .value_parser(["unstranded", "fr-firststrand", "fr-secondstrand"])
+                .help("Library strandedness used to assign junction strand from read orientation and mate flags"),
+            Arg::new("strand_source")
+                .long("strand-source")
+                .default_value("library")
+                .value_delimiter(',')
+                .value_parser(["library", "xs", "ts", "motif"])
+                .help("Sources of the junction strand in order of precedence, comma-separated: the --library-type ('library'), the XS tag ('xs'), the minimap2 ts tag ('ts') or the splice motif in the --reference ('motif', last only)"),
+            Arg::new("min_count")
+                .long("min-count")
+                .default_value("1")
+                .value_parser(clap::value_parser!(u32))
+                .help("Minimum number of reads for a junction to be written"),
+            Arg::new("max_count")
+                .long("max-count")
+                .value_parser(clap::value_parser!(u32))
+                .help("Maximum number of reads for a junction to be written (e.g., to drop rRNA or artifact junctions), summed across barcodes in single mode"),
+            Arg::new("collapse_radius")
+                .long("collapse-radius")
+                .value_parser(clap::value_parser!(i64))
+                .help("Merge junctions whose donor and acceptor are each within this many bp onto the highest-count junction"),
+            Arg::new("gtf")
+                .short('g')
+                .long("gtf")
+                .value_parser(clap::value_parser!(String))
+                .help("Optional GTF file to annotate junctions with known introns and genes"),
+            Arg::new("gene_level")
+                .long("gene-level")
+                .action(clap::ArgAction::SetTrue)
+                .requires("gtf")
+                .help("Also write junction counts summed by gene (requires --gtf)"),
+            Arg::new("drop_ambiguous_genes")
+                .long("drop-ambiguous-genes")
+                .action(clap::ArgAction::SetTrue)
+                .requires("gene_level")
+                .help("Skip junctions within multiple genes in --gene-level output instead of counting them for each gene"),
+            Arg::new("add_chr_prefix")
+                .long("add-chr-prefix")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with("strip_chr_prefix")
+                .help("Add the 'chr' prefix to chromosome names lacking it (e.g., 1 -> chr1)"),
+            Arg::new("strip_chr_prefix")
+                .long("strip-chr-prefix")
+                .action(clap::ArgAction::SetTrue)
+                .help("Strip the 'chr' prefix from chromosome names (e.g., chr1 -> 1)"),
+            Arg::new("coord_base")
+                .long("coord-base")
+                .value_parser(["0", "1"])
+                .help("Write junctions as intron coordinates, 1-based (as STAR SJ.out.tab) or 0-based half-open (as BED), instead of the flanking exon bases"),
+            Arg::new("include_chroms")
+                .long("include-chroms")
+                .value_parser(clap::value_parser!(String))
+                .help("Only count reads on these chromosomes (comma-separated list or file with one name per line)"),
+            Arg::new("exclude_chroms")
+                .long("exclude-chroms")
+                .value_parser(clap::value_parser!(String))
+                .help("Skip reads on these chromosomes (comma-separated list or file with one name per line)"),
+            Arg::new("mito_name")
+                .long("mito-name")
+                .default_value("chrM,MT")
+                .value_parser(clap::value_parser!(String))
+                .help("Names of the mitochondrial contig for the statistics of mitochondrial reads (comma-separated)"),
+            Arg::new("dedup_by_position")
+                .long("dedup-by-position")
+                .action(clap::ArgAction::SetTrue)
+                .help("Count one read per alignment start (per barcode in single mode) for each junction instead of deduplicating by read name or UMI, for BAM files without duplicate marking"),
+            Arg::new("no_duplicates")
+                .long("no-duplicates")
+                .action(clap::ArgAction::SetTrue)
+                .help("Skip reads marked as PCR or optical duplicates (flag 0x400)"),
+            Arg::new("proper_pairs_only")
+                .long("proper-pairs-only")
+                .action(clap::ArgAction::SetTrue)
+                .help("Skip paired reads not mapped in a proper pair (flag 0x2), such as discordant pairs and singletons"),
+            Arg::new("subsample")
+                .long("subsample")
+                .value_parser(clap::value_parser!(f64))
+                .help("Keep each read with the given probability (0-1); mates of a fragment are sampled independently"),
+            Arg::new("seed")
+                .long("seed")
+                .default_value("0")
+                .value_parser(clap::value_parser!(u64))
+                .help("Random seed for --subsample"),
+            Arg::new("two_pass")
+                .long("two-pass")
+                .action(clap::ArgAction::SetTrue)
+                .help("Collect supported junctions in a first pass and count reads in a second pass instead of buffering reads"),
+            Arg::new("strict")
+                .long("strict")
+                .action(clap::ArgAction::SetTrue)
+                .help("Fail instead of warning if the input is not sorted by coordinate"),
+            Arg::new("distinct_reads")
+                .long("distinct-reads")
+                .action(clap::ArgAction::SetTrue)
+                .help("Report the number of distinct reads supporting any junction in the run statistics (keeps all counted read names in memory)"),
+            Arg::new("keep_unsupported")
+                .long("keep-unsupported")
+                .action(clap::ArgAction::SetTrue)
+                .help("Write junctions never supported by an anchored read, with their number of reads, to unsupported.tsv"),
+            Arg::new("microexons")
+                .long("microexons")
+                .value_parser(clap::value_parser!(i64).range(1..))
+                .help("Write reads spanning exons up to this length between two anchored junctions (microexons) to microexon.tsv"),
+            Arg::new("spanning_bam")
+                .long("spanning-bam")
+                .value_parser(clap::value_parser!(String))
+                .help("Optional BAM file to write the counted junction-spanning reads"),
+            Arg::new("read_map")
+                .long("read-map")
+                .value_parser(clap::value_parser!(String))
+                .help("Optional TSV file to write each counted read with its barcode and junction, gzip-compressed unless --no-compress"),
+            Arg::new("bedgraph")
+                .long("bedgraph")
+                .value_parser(clap::value_parser!(String))
+                .help("Optional bedGraph file to write the intron span of each junction with its count, e.g., as a track for genome browsers (gzip-compressed if ending with .gz)"),
+            Arg::new("compression_level")
+                .long("compression-level")
+                .default_value("6")
+                .value_parser(clap::value_parser!(u32).range(0..=9))
+                .help("Gzip compression level for output files (0-9)"),
+            Arg::new("no_compress")
+                .long("no-compress")
+                .action(clap::ArgAction::SetTrue)
+                .help("Write uncompressed output files without the .gz suffix"),
+            Arg::new("stats_json")
+                .long("stats-json")
+                .value_parser(clap::value_parser!(String))
+                .help("Optional JSON file to write run statistics"),
+            Arg::new("dump_config")
+                .long("dump-config")
+                .value_parser(clap::value_parser!(String))
+                .help("Optional JSON file to write the effective configuration of the run"),
+            Arg::new("threads")
+                .short('t')
+                .long("threads")
+                .default_value("1")
+                .value_parser(clap::value_parser!(usize))
+                .help("Number of threads for processing chromosomes in parallel and decompressing BAM blocks"),
+            Arg::new("progress")
+                .long("progress")
+                .action(clap::ArgAction::SetTrue)
+                .help("Show a progress bar (default when stderr is a terminal)"),
+            Arg::new("progress_interval")
+                .long("progress-interval")
+                .default_value("1")
+                .value_parser(clap::value_parser!(u64).range(1..=100))
+                .help("Percentage of reads between progress lines in the log (with --verbose and without a progress bar), e.g., 5 for every 5%"),
+    ]
+}
+
+// Function to get an option that is only defined for some subcommands
+fn get_optional<'a, T: Clone + Send + Sync + 'static>(matches: &'a ArgMatches, id: &str) -> Option<&'a T> {
+    matches.try_get_one::<T>(id).ok().flatten()
+}
+
+// Function to check whether an option was left at its default value, so that a preset can replace it
+fn is_default(matches: &ArgMatches, id: &str) -> bool {
+    matches.value_source(id) == Some(ValueSource::DefaultValue)
+}
+
+// Function to get a flag that is only defined for some subcommands, false if undefined
+fn get_optional_flag(matches: &ArgMatches, id: &str) -> bool {
+    get_optional::<bool>(matches, id).copied().unwrap_or(false)
+}
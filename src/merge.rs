@@ -0,0 +1,163 @@
+// Modules for merging per-sample single-mode matrices
+use std::collections::{HashMap, HashSet};
+use std::io::BufRead;
+use std::path::Path;
+use std::str::FromStr;
+use flate2::Compression;
+use log::info;
+
+use crate::data_loader;
+use crate::writer::{self, MatrixValue};
+
+// Function to find an output file of a sample directory, with or without the .gz suffix
+fn find_input(input_dir: &str, file_name: &str) -> Result<String, Box<dyn std::error::Error>> {
+    for path in [format!("{}/{}.gz", input_dir, file_name), format!("{}/{}", input_dir, file_name)] {
+        if Path::new(&path).is_file() {
+            return Ok(path);
+        }
+    }
+    Err(format!("{} not found in {}", file_name, input_dir).into())
+}
+
+// Function to read the first column of a features or barcodes file
+fn read_first_column(file_path: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut values = Vec::new();
+    for line in data_loader::open_text(file_path)?.lines() {
+        let line = line?;
+        if let Some(value) = line.split('\t').next() {
+            values.push(value.trim().to_string());
+        }
+    }
+    Ok(values)
+}
+
+// Feature of a 10x-style features file: ID, name and type
+type Feature = (String, String, String);
+
+// Function to read the IDs of a 10x-style features file with their names and types, which default to
+// the ID and "Junction" for files with fewer columns
+fn read_features(file_path: &str) -> Result<Vec<Feature>, Box<dyn std::error::Error>> {
+    let mut features = Vec::new();
+    for line in data_loader::open_text(file_path)?.lines() {
+        let line = line?;
+        let mut fields = line.split('\t').map(str::trim);
+        let id = fields.next().unwrap_or_default().to_string();
+        let name = fields.next().map_or_else(|| id.clone(), str::to_string);
+        let feature_type = fields.next().unwrap_or("Junction").to_string();
+        features.push((id, name, feature_type));
+    }
+    Ok(features)
+}
+
+// Function to get the sample tag used to prefix barcodes from the sample directory name
+fn sample_tag(input_dir: &str) -> String {
+    Path::new(input_dir.trim_end_matches('/'))
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| input_dir.to_string())
+}
+
+// Function to get the field ("integer" or "real") from the Matrix Market header of a matrix
+fn matrix_field(matrix_path: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut header = String::new();
+    data_loader::open_text(matrix_path)?.read_line(&mut header)?;
+    match header.strip_prefix("%%MatrixMarket").and_then(|header| header.split_whitespace().nth(2)) {
+        Some(field) => Ok(field.to_lowercase()),
+        None => Err(format!("Missing Matrix Market header in {}", matrix_path).into()),
+    }
+}
+
+// Function to merge the matrices of several samples into one feature-by-barcode matrix,
+// with real counts if the samples were counted with --fractional-multimappers
+pub fn merge_matrices(
+    input_dirs: &[String],
+    output_dir: &str,
+    compression: Option<Compression>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut fields = Vec::new();
+    for input_dir in input_dirs {
+        fields.push(matrix_field(&find_input(input_dir, "matrix.mtx")?)?);
+    }
+    if fields.iter().any(|field| *field != fields[0]) {
+        return Err("Cannot merge integer matrices with real matrices (--fractional-multimappers)".into());
+    }
+    match fields.first().map(String::as_str) {
+        Some("real") => merge_matrices_of::<f64>(input_dirs, output_dir, compression),
+        Some("integer") | None => merge_matrices_of::<u32>(input_dirs, output_dir, compression),
+        Some(field) => Err(format!("Unsupported Matrix Market field '{}'", field).into()),
+    }
+}
+
+// Function to merge matrices whose values are all of one type
+fn merge_matrices_of<T>(
+    input_dirs: &[String],
+    output_dir: &str,
+    compression: Option<Compression>,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    T: MatrixValue + FromStr,
+    T::Err: std::error::Error + 'static,
+{
+    let mut junction_counts: HashMap<String, HashMap<String, T>> = HashMap::new();
+    let mut feature_names: HashMap<String, String> = HashMap::new();
+    let mut feature_type: Option<String> = None; // Type shared by the features of all samples (e.g., "Gene")
+    let mut cell_barcodes: HashSet<String> = HashSet::new();
+    let mut sample_tags: HashSet<String> = HashSet::new();
+
+    for input_dir in input_dirs {
+        let tag = sample_tag(input_dir);
+        if !sample_tags.insert(tag.clone()) {
+            return Err(format!("Duplicate sample name '{}'; input directories must have distinct names", tag).into());
+        }
+        info!("Merging sample {} ({})", tag, input_dir);
+
+        let features_path = find_input(input_dir, "features.tsv")?;
+        let mut features = Vec::new();
+        for (id, name, sample_feature_type) in read_features(&features_path)? {
+            match &feature_type {
+                Some(feature_type) if *feature_type != sample_feature_type => {
+                    return Err(format!("Cannot merge {} features of {} with {} features", sample_feature_type, features_path, feature_type).into());
+                }
+                Some(_) => {}
+                None => feature_type = Some(sample_feature_type),
+            }
+            feature_names.insert(id.clone(), name);
+            features.push(id);
+        }
+        let barcodes: Vec<String> = read_first_column(&find_input(input_dir, "barcodes.tsv")?)?
+            .into_iter()
+            .map(|barcode| format!("{}_{}", tag, barcode))
+            .collect();
+        cell_barcodes.extend(barcodes.iter().cloned());
+
+        // Read the sparse matrix entries, skipping comments and the dimension line
+        let matrix_path = find_input(input_dir, "matrix.mtx")?;
+        let mut header_seen = false;
+        for line in data_loader::open_text(&matrix_path)?.lines() {
+            let line = line?;
+            if line.starts_with('%') {
+                continue;
+            }
+            if !header_seen {
+                header_seen = true;
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 3 {
+                return Err(format!("Malformed matrix entry in {}: {}", matrix_path, line).into());
+            }
+            let i = fields[0].parse::<usize>()?;
+            let j = fields[1].parse::<usize>()?;
+            let count = fields[2].parse::<T>()?;
+            let feature = i.checked_sub(1).and_then(|i| features.get(i)).ok_or(format!("Feature index {} out of range in {}", i, matrix_path))?;
+            let barcode = j.checked_sub(1).and_then(|j| barcodes.get(j)).ok_or(format!("Barcode index {} out of range in {}", j, matrix_path))?;
+            let cell_counts = junction_counts.entry(feature.clone()).or_default();
+            let merged = cell_counts.get(barcode).map_or(count, |&merged| merged + count);
+            cell_counts.insert(barcode.clone(), merged);
+        }
+    }
+
+    info!("Merged {} features and {} barcodes", junction_counts.len(), cell_barcodes.len());
+    let feature_type = feature_type.unwrap_or_else(|| "Junction".to_string());
+    writer::write_matrix(output_dir, &junction_counts, &cell_barcodes, Some(&feature_names), &feature_type, compression)
+}
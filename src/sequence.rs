@@ -0,0 +1,128 @@
+// Modules for fetching splice-site sequences from the reference FASTA
+use rust_htslib::faidx;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::junction::{self, ChrPrefix};
+
+// Reader of the donor and acceptor sequences of junctions from an indexed reference FASTA
+pub struct SpliceSiteReader {
+    reader: faidx::Reader,
+    sequences: HashMap<String, (String, i64)>, // FASTA name and length by chromosome name of the junction keys
+    flank: i64, // Number of exon bases added to the outer side of each dinucleotide
+}
+
+impl SpliceSiteReader {
+    // Function to open the reference FASTA, building its index (.fai) if it is missing
+    pub fn from_path(path: &str, chr_prefix: ChrPrefix, flank: i64) -> Result<Self, Box<dyn std::error::Error>> {
+        if !Path::new(path).is_file() {
+            return Err(format!("Reference FASTA '{}' does not exist", path).into());
+        }
+        if !Path::new(&format!("{}.fai", path)).exists() {
+            faidx::build(path)?;
+        }
+        let reader = faidx::Reader::from_path(path)?;
+        // Chromosomes are renamed like the junction keys so that --add/--strip-chr-prefix still match the FASTA
+        let sequences = reader
+            .seq_names()?
+            .into_iter()
+            .map(|name| {
+                let length = reader.fetch_seq_len(&name) as i64;
+                (chr_prefix.apply(&name), (name, length))
+            })
+            .collect();
+        Ok(SpliceSiteReader { reader, sequences, flank })
+    }
+
+    // Function to fetch the sequence of a 1-based inclusive range, or None if it is outside the chromosome
+    fn fetch(&self, chrom: &str, start: i64, end: i64) -> Option<String> {
+        let (name, length) = self.sequences.get(chrom)?;
+        if start < 1 || end > *length {
+            return None;
+        }
+        let sequence = self.reader.fetch_seq_string(name, (start - 1) as usize, (end - 1) as usize).ok()?;
+        Some(sequence.to_uppercase())
+    }
+
+    // Function to get the donor and acceptor sequences of a junction, i.e., the first and last intron dinucleotides
+    // with flanking exon bases. Sequences of minus-strand junctions are reverse-complemented, and those of junctions
+    // without a strand are read on the plus strand. "." is returned for sites outside the reference.
+    pub fn splice_sites(&self, junction_coords: &str) -> (String, String) {
+        let (_, strand) = junction::split_strand(junction_coords);
+        let (chrom, start, end) = match junction::parse_junction(junction_coords) {
+            Some(coords) => coords,
+            None => return (".".to_string(), ".".to_string()),
+        };
+        let left = self.fetch(chrom, start - self.flank + 1, start + 2);
+        let right = self.fetch(chrom, end - 2, end + self.flank - 1);
+        let (donor, acceptor) = if strand == Some("-") {
+            (right.map(|sequence| reverse_complement(&sequence)), left.map(|sequence| reverse_complement(&sequence)))
+        } else {
+            (left, right)
+        };
+        (donor.unwrap_or_else(|| ".".to_string()), acceptor.unwrap_or_else(|| ".".to_string()))
+    }
+
+    // Function to get the STAR intron motif code of a junction from its first and last intron dinucleotides
+    // on the plus strand, regardless of the strand of the junction and of --splice-site-flank
+    pub fn motif(&self, junction_coords: &str) -> u8 {
+        let (chrom, start, end) = match junction::parse_junction(junction_coords) {
+            Some(coords) => coords,
+            None => return 0,
+        };
+        match (self.fetch(chrom, start + 1, start + 2), self.fetch(chrom, end - 2, end - 1)) {
+            (Some(left), Some(right)) => star_motif(&left, &right),
+            _ => 0,
+        }
+    }
+
+    // Function to get the strand of the splice motif of a junction, or None for a non-canonical motif
+    pub fn motif_strand(&self, junction_coords: &str) -> Option<char> {
+        match self.motif(junction_coords) {
+            0 => None,
+            motif if motif % 2 == 1 => Some('+'),
+            _ => Some('-'),
+        }
+    }
+
+    // Function to append the strand of the splice motif to a junction without a strand, keeping
+    // non-canonical junctions (motif 0) and junctions that already have a strand as they are
+    pub fn assign_strand(&self, junction_coords: String) -> String {
+        if junction::split_strand(&junction_coords).1.is_some() {
+            return junction_coords;
+        }
+        match self.motif_strand(&junction_coords) {
+            Some(strand) => format!("{}:{}", junction_coords, strand),
+            None => junction_coords,
+        }
+    }
+}
+
+// Function to classify the first and last intron dinucleotides (plus strand) with the intron motif codes of STAR:
+// 0 non-canonical, 1 GT/AG, 2 CT/AC, 3 GC/AG, 4 CT/GC, 5 AT/AC, 6 GT/AT (odd codes on the plus strand, even on the minus)
+pub fn star_motif(left: &str, right: &str) -> u8 {
+    match (left, right) {
+        ("GT", "AG") => 1,
+        ("CT", "AC") => 2,
+        ("GC", "AG") => 3,
+        ("CT", "GC") => 4,
+        ("AT", "AC") => 5,
+        ("GT", "AT") => 6,
+        _ => 0,
+    }
+}
+
+// Function to reverse-complement a DNA sequence, keeping bases other than A, C, G and T (e.g., N)
+fn reverse_complement(sequence: &str) -> String {
+    sequence
+        .chars()
+        .rev()
+        .map(|base| match base {
+            'A' => 'T',
+            'C' => 'G',
+            'G' => 'C',
+            'T' => 'A',
+            other => other,
+        })
+        .collect()
+}
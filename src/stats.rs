@@ -0,0 +1,101 @@
+// Modules for reporting run statistics
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+// Summary of a run written by --stats-json
+#[derive(Serialize)]
+pub struct RunStats {
+    pub total_reads: u64,
+    pub processed_reads: u64,
+    pub skipped_nh: u64,
+    pub skipped_anchor: u64,
+    pub skipped_intron_length: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skipped_barcode: Option<u64>, // Reads lacking a barcode tag or with a barcode not of interest (single mode)
+    pub junctions: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub barcodes: Option<usize>,
+    pub counted_reads: u64,
+    pub spliced_fraction: f64, // Fraction of processed reads counted into any junction
+    pub mito_reads: u64,
+    pub mito_junctions: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distinct_reads: Option<usize>, // Reads counted into any junction, each counted once
+}
+
+// Effective parameters of a run written by --dump-config
+#[derive(Serialize)]
+pub struct RunConfig {
+    pub version: String,
+    pub mode: String,
+    pub bam_files: Vec<String>,
+    pub output_dir: String,
+    pub reference: Option<String>,
+    pub gtf: Option<String>,
+    pub preset: Option<String>,
+    pub gene_level: bool,
+    pub drop_ambiguous_genes: bool,
+    pub anchor_length: i64,
+    pub min_left_anchor: Option<i64>,
+    pub min_right_anchor: Option<i64>,
+    pub min_intron_length: i64,
+    pub max_intron_length: i64,
+    pub max_loci: u32,
+    pub fractional_multimappers: bool,
+    pub require_nh: bool,
+    pub min_aligned_length: i64,
+    pub min_boundary_qual: u8,
+    pub min_insert_size: Option<i64>,
+    pub max_insert_size: Option<i64>,
+    pub library_type: String,
+    pub strand_source: Vec<String>,
+    pub min_count: u32,
+    pub max_count: Option<u32>,
+    pub min_cells: Option<u32>,
+    pub min_barcode_count: Option<u32>,
+    pub collapse_radius: Option<i64>,
+    pub intron_retention: bool,
+    pub split_output: bool,
+    pub splice_site_seq: bool,
+    pub splice_motif: bool,
+    pub splice_site_flank: i64,
+    pub cpm: bool,
+    pub include_chroms: Option<String>,
+    pub exclude_chroms: Option<String>,
+    pub mito_name: String,
+    pub chr_prefix: String,
+    pub coord_base: String,
+    pub cell_barcodes: Vec<String>,
+    pub cell_barcode_tags: Vec<String>,
+    pub barcode_from_name: Option<String>,
+    pub strip_barcode_suffix: bool,
+    pub umi_tag: String,
+    pub count_umis: bool,
+    pub no_duplicates: bool,
+    pub dedup_by_position: bool,
+    pub proper_pairs_only: bool,
+    pub subsample: Option<f64>,
+    pub seed: u64,
+    pub split_by_rg: bool,
+    pub two_pass: bool,
+    pub strict: bool,
+    pub distinct_reads: bool,
+    pub keep_unsupported: bool,
+    pub microexons: Option<i64>,
+    pub spanning_bam: Option<String>,
+    pub read_map: Option<String>,
+    pub bedgraph: Option<String>,
+    pub dropped_barcodes: Option<String>,
+    pub compression_level: Option<u32>, // None if output is uncompressed
+    pub threads: usize,
+    pub progress_interval: u64,
+}
+
+// Function to write run statistics or configuration as JSON
+pub fn write_json<T: Serialize>(path: &str, value: &T) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(&mut file, value)?;
+    writeln!(file)?;
+    Ok(())
+}
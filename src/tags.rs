@@ -0,0 +1,103 @@
+// Modules for handling aux tags
+use rust_htslib::bam::record::{Aux, Record};
+
+// Function to get the number of loci (NH) the read maps to, regardless of integer type
+pub fn get_nh(record: &Record) -> Option<u64> {
+    match record.aux(b"NH") {
+        Ok(Aux::I8(nh)) => Some(nh.max(0) as u64),
+        Ok(Aux::U8(nh)) => Some(nh as u64),
+        Ok(Aux::I16(nh)) => Some(nh.max(0) as u64),
+        Ok(Aux::U16(nh)) => Some(nh as u64),
+        Ok(Aux::I32(nh)) => Some(nh.max(0) as u64),
+        Ok(Aux::U32(nh)) => Some(nh as u64),
+        _ => None,
+    }
+}
+
+// Function to get the strand of the splice motif (XS) set by aligners such as STAR and HISAT2
+pub fn get_xs(record: &Record) -> Option<char> {
+    match record.aux(b"XS") {
+        Ok(Aux::Char(b'+')) => Some('+'),
+        Ok(Aux::Char(b'-')) => Some('-'),
+        _ => None,
+    }
+}
+
+// Function to get the genomic strand of the transcript from the minimap2 ts tag, which is relative to the read
+pub fn get_ts(record: &Record) -> Option<char> {
+    let same_strand = match record.aux(b"ts") {
+        Ok(Aux::Char(b'+')) => true,
+        Ok(Aux::Char(b'-')) => false,
+        _ => return None,
+    };
+    // The transcript lies on the plus strand if it has the strand of a forward read or the opposite of a reverse read
+    if same_strand != record.is_reverse() { Some('+') } else { Some('-') }
+}
+
+// Extraction of cell barcodes from read names (e.g., "readid_AACCGGTT") for BAM files without barcode tags
+pub enum NameBarcode {
+    Delimiter(String), // Barcode after the last occurrence of the delimiter
+    Regex(regex::Regex), // Barcode in the first capture group
+}
+
+impl NameBarcode {
+    // Function to parse a regex with a capture group, or otherwise a delimiter
+    pub fn parse(value: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        if value.is_empty() {
+            return Err("--barcode-from-name must not be empty".into());
+        }
+        if !value.contains('(') {
+            return Ok(NameBarcode::Delimiter(value.to_string()));
+        }
+        let regex = regex::Regex::new(value).map_err(|error| format!("Invalid --barcode-from-name regex: {}", error))?;
+        if regex.captures_len() < 2 {
+            return Err(format!("--barcode-from-name regex '{}' has no capture group", value).into());
+        }
+        Ok(NameBarcode::Regex(regex))
+    }
+
+    // Function to get the barcode of a read name, if any
+    pub fn extract<'a>(&self, read_name: &'a str) -> Option<&'a str> {
+        let barcode = match self {
+            NameBarcode::Delimiter(delimiter) => read_name.rsplit_once(delimiter.as_str()).map(|(_, barcode)| barcode),
+            NameBarcode::Regex(regex) => regex.captures(read_name).and_then(|captures| captures.get(1)).map(|barcode| barcode.as_str()),
+        };
+        barcode.filter(|barcode| !barcode.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::junction::JunctionExtractor;
+
+    // Function to build a record with the NH tag of a given integer type
+    fn record_with_nh(nh: Aux) -> Record {
+        let mut record = Record::new();
+        record.push_aux(b"NH", nh).unwrap();
+        record
+    }
+
+    #[test]
+    fn get_nh_reads_each_integer_type() {
+        for nh in [Aux::U8(3), Aux::I8(3), Aux::U16(3), Aux::I16(3), Aux::U32(3), Aux::I32(3)] {
+            assert_eq!(get_nh(&record_with_nh(nh)), Some(3));
+        }
+        assert_eq!(get_nh(&record_with_nh(Aux::I8(-1))), Some(0));
+        assert_eq!(get_nh(&Record::new()), None);
+    }
+
+    #[test]
+    fn passes_max_loci_for_each_integer_type() {
+        let extractor = JunctionExtractor::builder().max_loci(2).build();
+        for nh in [Aux::U8(2), Aux::I8(2), Aux::U16(2), Aux::I16(2), Aux::U32(2), Aux::I32(2)] {
+            assert!(extractor.passes_max_loci(&record_with_nh(nh)));
+        }
+        for nh in [Aux::U8(3), Aux::I8(3), Aux::U16(3), Aux::I16(3), Aux::U32(3), Aux::I32(3)] {
+            assert!(!extractor.passes_max_loci(&record_with_nh(nh)));
+        }
+        // Reads without NH pass unless it is required
+        assert!(extractor.passes_max_loci(&Record::new()));
+        assert!(!JunctionExtractor::builder().require_nh(true).build().passes_max_loci(&Record::new()));
+    }
+}
@@ -0,0 +1,382 @@
+// Modules for writing output files
+use rust_htslib::bam::{self, Read};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use itertools::Itertools;
+use log::debug;
+use std::collections::{HashMap, HashSet};
+
+use crate::junction::{self, CoordBase};
+
+// Buffered output file, optionally gzip-compressed, that must be finished to flush all data
+pub enum OutputFile {
+    Plain(BufWriter<Box<dyn Write>>),
+    Gzip(GzEncoder<BufWriter<Box<dyn Write>>>),
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputFile::Plain(writer) => writer.write(buf),
+            OutputFile::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputFile::Plain(writer) => writer.flush(),
+            OutputFile::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+impl OutputFile {
+    // Function to write the gzip trailer and flush the buffer, propagating any error
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            OutputFile::Plain(mut writer) => writer.flush(),
+            OutputFile::Gzip(encoder) => encoder.finish()?.flush(),
+        }
+    }
+}
+
+// Function to create the output directory if needed, failing early if the path is an existing file
+pub fn prepare_output_dir(output_dir: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if output_dir == "-" {
+        return Ok(());
+    }
+    let path = std::path::Path::new(output_dir);
+    if path.exists() && !path.is_dir() {
+        return Err(format!("Output directory '{}' is an existing file", output_dir).into());
+    }
+    fs::create_dir_all(path)?;
+    Ok(())
+}
+
+// Function to create an output file in the output directory, or stdout if the directory is "-".
+// The file is gzip-compressed with a ".gz" suffix unless compression is None.
+pub fn create_output(output_dir: &str, file_name: &str, compression: Option<Compression>) -> io::Result<OutputFile> {
+    let output: Box<dyn Write> = if output_dir == "-" {
+        Box::new(io::stdout())
+    } else {
+        match compression {
+            Some(_) => Box::new(File::create(format!("{}/{}.gz", output_dir, file_name))?),
+            None => Box::new(File::create(format!("{}/{}", output_dir, file_name))?),
+        }
+    };
+    match compression {
+        Some(level) => Ok(OutputFile::Gzip(GzEncoder::new(BufWriter::new(output), level))),
+        None => Ok(OutputFile::Plain(BufWriter::new(output))),
+    }
+}
+
+// Function to create an output file at the given path, gzip-compressed (without adding a suffix) unless compression is None
+pub fn create_output_path(path: &str, compression: Option<Compression>) -> io::Result<OutputFile> {
+    let output: Box<dyn Write> = Box::new(File::create(path)?);
+    match compression {
+        Some(level) => Ok(OutputFile::Gzip(GzEncoder::new(BufWriter::new(output), level))),
+        None => Ok(OutputFile::Plain(BufWriter::new(output))),
+    }
+}
+
+// Count types of the written matrices: read counts, or counts of reads weighted by 1/NH (--fractional-multimappers)
+pub trait MatrixValue: Copy + std::fmt::Display + std::iter::Sum + std::ops::Add<Output = Self> {
+    const FIELD: &'static str; // Field of the Matrix Market header
+}
+
+impl MatrixValue for u32 {
+    const FIELD: &'static str = "integer";
+}
+
+impl MatrixValue for f64 {
+    const FIELD: &'static str = "real";
+}
+
+// Function to write the sparse feature-by-barcode matrix with its features and barcodes
+pub fn write_matrix<T: MatrixValue>(
+    output_dir: &str,
+    junction_counts: &HashMap<String, HashMap<String, T>>,
+    cell_barcodes: &HashSet<String>,
+    feature_names: Option<&HashMap<String, String>>,
+    feature_type: &str,
+    compression: Option<Compression>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Prepare output files with optional compression
+    let mut matrix_file = create_output(output_dir, "matrix.mtx", compression)?;
+    let mut barcodes_file = create_output(output_dir, "barcodes.tsv", compression)?;
+    let mut features_file = create_output(output_dir, "features.tsv", compression)?;
+    let tsv_name = format!("{}_barcodes.tsv", feature_type.to_lowercase());
+    let mut output_tsv = create_output(output_dir, &tsv_name, compression)?;
+
+    // Write barcodes.tsv.gz
+    debug!("Writing barcodes.tsv.gz");
+    let barcode_list: Vec<_> = cell_barcodes.iter().sorted().collect();
+    for barcode in &barcode_list {
+        writeln!(barcodes_file, "{}", barcode)?;
+    }
+
+    // Write features.tsv.gz
+    debug!("Writing features.tsv.gz");
+    let feature_list: Vec<_> = junction_counts.keys().sorted().collect();
+    for feature in &feature_list {
+        // 10x-style features (id, name, type), named after the feature itself if no name is given (e.g., intergenic junctions)
+        let name = feature_names.and_then(|names| names.get(*feature)).unwrap_or(feature);
+        writeln!(features_file, "{}\t{}\t{}", feature, name, feature_type)?;
+    }
+
+    // Write the header lines of matrix.mtx.gz, with the number of non-zero entries computed up front
+    // from the entries that are written, i.e., those of barcodes in the barcode list
+    let barcode_map: HashMap<_, _> = barcode_list.iter().enumerate().map(|(i, b)| (b.as_str(), i)).collect();
+    let entries = junction_counts
+        .values()
+        .flat_map(|cell_counts| cell_counts.keys())
+        .filter(|barcode| barcode_map.contains_key(barcode.as_str()))
+        .count();
+    writeln!(matrix_file, "%%MatrixMarket matrix coordinate {} general", T::FIELD)?;
+    writeln!(matrix_file, "%")?;
+    writeln!(matrix_file, "{} {} {}", feature_list.len(), barcode_list.len(), entries)?;
+
+    // Write sparse matrix data and TSV data directly as they are generated
+    debug!("Writing matrix.mtx.gz and {}.gz", tsv_name);
+    writeln!(output_tsv, "Feature\tBarcode\tCount")?;
+    for (i, feature) in feature_list.iter().enumerate() {
+        if let Some(cell_counts) = junction_counts.get(*feature) {
+            for (barcode, count) in cell_counts {
+                if let Some(&j) = barcode_map.get(barcode.as_str()) {
+                    writeln!(matrix_file, "{} {} {}", i + 1, j + 1, count)?;
+                    writeln!(output_tsv, "{}\t{}\t{}", feature, barcode, count)?;
+                }
+            }
+        }
+    }
+
+    matrix_file.finish()?;
+    barcodes_file.finish()?;
+    features_file.finish()?;
+    output_tsv.finish()?;
+    Ok(())
+}
+
+// Function to write total read counts per junction, summed over barcodes in single mode
+pub fn write_junction_totals<T: MatrixValue>(
+    output_dir: &str,
+    junction_counts: &HashMap<String, HashMap<String, T>>,
+    compression: Option<Compression>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut output_file = create_output(output_dir, "junction.tsv", compression)?;
+    debug!("Writing junction.tsv.gz");
+    writeln!(output_file, "Junction\tCount")?;
+    for (junction, cell_counts) in junction_counts.iter().sorted_by(|a, b| a.0.cmp(b.0)) {
+        writeln!(output_file, "{}\t{}", junction, cell_counts.values().copied().sum::<T>())?;
+    }
+    output_file.finish()?;
+
+    Ok(())
+}
+
+// Function to write read counts per splice site
+pub fn write_site_counts(
+    output_dir: &str,
+    file_name: &str,
+    site_totals: &HashMap<String, u32>,
+    compression: Option<Compression>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut output_file = create_output(output_dir, file_name, compression)?;
+    debug!("Writing {}", file_name);
+    writeln!(output_file, "Site\tCount")?;
+    for (site, count) in site_totals.iter().sorted() {
+        writeln!(output_file, "{}\t{}", site, count)?;
+    }
+    output_file.finish()?;
+
+    Ok(())
+}
+
+// Function to write read counts per gene with the names of the genes
+pub fn write_gene_counts(
+    output_dir: &str,
+    gene_totals: &HashMap<String, u32>,
+    gene_names: &HashMap<String, String>,
+    compression: Option<Compression>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut output_file = create_output(output_dir, "gene.tsv", compression)?;
+    debug!("Writing gene.tsv.gz");
+    writeln!(output_file, "GeneID\tGeneName\tCount")?;
+    for (gene_id, count) in gene_totals.iter().sorted() {
+        writeln!(output_file, "{}\t{}\t{}", gene_id, gene_names[gene_id], count)?;
+    }
+    output_file.finish()?;
+
+    Ok(())
+}
+
+// Function to write junctions never supported by an anchored read with the number of their reads
+pub fn write_unsupported_junctions(
+    output_dir: &str,
+    unsupported_totals: &HashMap<String, u32>,
+    reference_names: &[String],
+    compression: Option<Compression>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut output_file = create_output(output_dir, "unsupported.tsv", compression)?;
+    debug!("Writing unsupported.tsv.gz");
+    writeln!(output_file, "Junction\tReads")?;
+    for junction in junction::sort_by_position(unsupported_totals.keys(), reference_names) {
+        writeln!(output_file, "{}\t{}", junction, unsupported_totals[junction])?;
+    }
+    output_file.finish()?;
+
+    Ok(())
+}
+
+// Function to write the intron spans of junctions with their counts as a bedGraph track (0-based half-open),
+// summing junctions with the same span on both strands. The file is gzip-compressed if the path ends with ".gz".
+pub fn write_bedgraph<T: MatrixValue>(
+    path: &str,
+    junction_totals: &HashMap<String, T>,
+    reference_names: &[String],
+    compression: Option<Compression>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut output_file = create_output_path(path, compression.filter(|_| path.ends_with(".gz")))?;
+    debug!("Writing {}", path);
+    writeln!(output_file, "track type=bedGraph name=\"junctions\" description=\"Junction read counts over intron spans\"")?;
+    // Junctions are sorted by span, so both strands of a span are adjacent
+    let spans = junction::sort_by_position(junction_totals.keys(), reference_names)
+        .into_iter()
+        .filter_map(|junction| junction::parse_junction(junction).map(|(chrom, start, end)| ((chrom, start, end), junction_totals[junction])))
+        .coalesce(|(span, count), (next_span, next_count)| {
+            if span == next_span {
+                Ok((span, count + next_count))
+            } else {
+                Err(((span, count), (next_span, next_count)))
+            }
+        });
+    for ((chrom, start, end), count) in spans {
+        // The intron spans from the base after the donor exon (0-based start) to the acceptor exon (exclusive end)
+        writeln!(output_file, "{}\t{}\t{}\t{}", chrom, start, end - 1, count)?;
+    }
+    output_file.finish()?;
+
+    Ok(())
+}
+
+// Function to write reads spanning microexons with the exon (1-based, inclusive), its length and strand,
+// and its upstream and downstream junctions in the output coordinates
+pub fn write_microexons(
+    output_dir: &str,
+    microexon_totals: &HashMap<(String, String), u32>,
+    reference_names: &[String],
+    coord_base: CoordBase,
+    compression: Option<Compression>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut output_file = create_output(output_dir, "microexon.tsv", compression)?;
+    debug!("Writing microexon.tsv.gz");
+    writeln!(output_file, "Microexon\tStrand\tLength\tUpstreamJunction\tDownstreamJunction\tCount")?;
+    // The exon spans from the first base after the upstream intron to the last base before the downstream intron
+    let mut microexons: HashMap<String, Vec<(&String, &String, u32)>> = HashMap::new();
+    for ((upstream, downstream), count) in microexon_totals {
+        if let (Some((chrom, _, start)), Some((_, end, _))) = (junction::parse_junction(upstream), junction::parse_junction(downstream)) {
+            let microexon = match junction::split_strand(upstream).1 {
+                Some(strand) => format!("{}:{}-{}:{}", chrom, start, end, strand),
+                None => format!("{}:{}-{}", chrom, start, end),
+            };
+            microexons.entry(microexon).or_default().push((upstream, downstream, *count));
+        }
+    }
+    for microexon in junction::sort_by_position(microexons.keys(), reference_names) {
+        let (coords, strand) = junction::split_strand(microexon);
+        let (_, start, end) = junction::parse_junction(coords).unwrap();
+        for (upstream, downstream, count) in microexons[microexon].iter().sorted() {
+            writeln!(
+                output_file,
+                "{}\t{}\t{}\t{}\t{}\t{}",
+                coords,
+                strand.unwrap_or("."),
+                end - start + 1,
+                coord_base.apply(upstream),
+                coord_base.apply(downstream),
+                count
+            )?;
+        }
+    }
+    output_file.finish()?;
+
+    Ok(())
+}
+
+// Function to write the barcodes skipped as not of interest with their number of reads, from the most reads.
+// The file is gzip-compressed if the path ends with ".gz".
+pub fn write_dropped_barcodes(
+    path: &str,
+    dropped_barcodes: &HashMap<String, u64>,
+    compression: Option<Compression>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut output_file = create_output_path(path, compression.filter(|_| path.ends_with(".gz")))?;
+    debug!("Writing {}", path);
+    writeln!(output_file, "Barcode\tReads")?;
+    for (barcode, count) in dropped_barcodes.iter().sorted_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0))) {
+        writeln!(output_file, "{}\t{}", barcode, count)?;
+    }
+    output_file.finish()?;
+
+    Ok(())
+}
+
+// Function to write the junction-by-sample count matrix of multiple BAM files
+pub fn write_sample_matrix(
+    output_dir: &str,
+    sample_counts: &HashMap<String, HashMap<String, u32>>,
+    sample_names: &[String],
+    reference_names: &[String],
+    compression: Option<Compression>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut output_file = create_output(output_dir, "junction.tsv", compression)?;
+    debug!("Writing junction.tsv.gz");
+    writeln!(output_file, "Junction\t{}", sample_names.join("\t"))?;
+    for junction in junction::sort_by_position(sample_counts.keys(), reference_names) {
+        let counts = &sample_counts[junction];
+        let row: Vec<String> = sample_names
+            .iter()
+            .map(|sample| counts.get(sample).copied().unwrap_or(0).to_string())
+            .collect();
+        writeln!(output_file, "{}\t{}", junction, row.join("\t"))?;
+    }
+    output_file.finish()?;
+
+    Ok(())
+}
+
+// Function to create a BAM writer for junction-spanning reads
+pub fn create_spanning_writer(path: &str, header: &bam::Header) -> Result<bam::Writer, rust_htslib::errors::Error> {
+    bam::Writer::from_path(path, header, bam::Format::Bam)
+}
+
+// Function to get the path of a per-chromosome file written by a worker thread, with a suffix by output
+// (e.g., "bam" or "reads") so that outputs sharing a path do not overwrite each other's files
+pub fn partial_path(path: &str, tid: u32, suffix: &str) -> String {
+    format!("{}.{}.{}.tmp", path, tid, suffix)
+}
+
+// Function to append per-chromosome text files to an output file in reference order and remove them
+pub fn concat_text_files(paths: &[String], output: &mut OutputFile) -> Result<(), Box<dyn std::error::Error>> {
+    for path in paths {
+        io::copy(&mut File::open(path)?, output)?;
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+// Function to concatenate per-chromosome BAM files in reference order and remove them
+pub fn concat_bams(paths: &[String], writer: &mut bam::Writer) -> Result<(), Box<dyn std::error::Error>> {
+    for path in paths {
+        {
+            let mut reader = bam::Reader::from_path(path)?;
+            for result in reader.records() {
+                writer.write(&result?)?;
+            }
+        }
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}